@@ -1,4 +1,4 @@
-use ricklepick::Parser;
+use ricklepick::{ExtensionContext, Parser, PickleError};
 use ricklepick::value::{Instance, Value};
 use std::fs::File;
 use std::io::{BufReader, Read};
@@ -26,22 +26,27 @@ fn main() {
     parser.add_extension("torch.storage", "_load_from_bytes", load_from_bytes);
     parser.add_extension("collections", "OrderedDict", ordered_dict);
     parser.add_extension("torch._utils", "_rebuild_tensor_v2", rebuild_tensor);
-    let result = parser.parse();
-    println!("{result}");
+    match parser.parse() {
+        Ok(result) => println!("{result}"),
+        Err(e) => eprintln!("failed to unpickle {model_file}: {e}"),
+    }
+    for warning in parser.warnings() {
+        eprintln!("warning: {warning}");
+    }
 }
 
 
-fn ordered_dict(val: Value) -> Value {
-    val
+fn ordered_dict<'b>(_: &mut ExtensionContext<'_, 'b>, val: Value<'b>) -> Result<Value<'b>, PickleError> {
+    Ok(val)
 }  
 
 // def _rebuild_tensor_v2(storage, storage_offset, size, stride, requires_grad, backward_hooks, metadata=None)
-fn rebuild_tensor(val: Value) -> Value {
+fn rebuild_tensor<'b>(_: &mut ExtensionContext<'_, 'b>, val: Value<'b>) -> Result<Value<'b>, PickleError> {
     if let Value::Tuple(arg) = val.clone() {
         let (tensor, _offset, _size, _stride, _required_grad) = rebuild_tensor_args_from_tuple(arg);
-        return Value::List(tensor);
+        return Ok(Value::List(tensor));
     }
-    val
+    Ok(val)
 }  
 
 fn rebuild_tensor_args_from_tuple(mut tuple: Vec<Value>) -> (Vec<Value>, u32, u32, u32, bool) {
@@ -85,36 +90,35 @@ fn storage_size(s: String) -> usize {
 }
 
 // https://pytorch.org/docs/stable/_modules/torch/serialization.html#load
-fn load_from_bytes(val: Value) -> Value {
+fn load_from_bytes<'b>(ctx: &mut ExtensionContext<'_, 'b>, val: Value<'b>) -> Result<Value<'b>, PickleError> {
     if let Value::Tuple(arg) = val {
         if let Value::Bytes(bytes) = &arg[0] {
             let mut buf = bytes.as_slice();
 
             // torch magic number
-            let mut parser = Parser::from(&mut buf);
-            let magic_number = parser.parse().as_long().unwrap();
+            let magic_number = Parser::from(&mut buf).parse().unwrap().as_long().unwrap();
             if magic_number != MAGIC_NUMBER {
                 panic!("Wrong magic number. Corrupted file?");
             }
 
             // torch protocol version
-            let mut parser = Parser::from(&mut buf);
-            let protocol_version = parser.parse().as_uint().unwrap();
+            let protocol_version = Parser::from(&mut buf).parse().unwrap().as_uint().unwrap();
             if protocol_version != PROTOCOL_VERSION {
                 panic!("Wrong protocl version. Got {protocol_version}");
             }
 
             // encoded sys info
-            let mut parser = Parser::from(&mut buf);
-            let _sys_info = parser.parse();
+            let _sys_info = Parser::from(&mut buf).parse().unwrap();
 
             // 
+            // The storage is referenced by a persistent id, keep the pid itself.
             let mut parser = Parser::from(&mut buf);
-            let args = parser.parse();
+            parser.set_persistent_load(Ok);
+            let args = parser.parse().unwrap();
+            drop(parser);
             let (_typename, storage_type, _root_key, _location, numel) = persistence_load_args(args);
-            println!("_load_from_bytes:\n\tPROTOCOL VERSION: {protocol_version}\n\tSYS_INFO: {_sys_info}\n\tLOADING TENSOR OF SIZE ({numel} * {})", storage_size(storage_type.name()));
-            let mut parser = Parser::from(&mut buf);
-            let _keys = parser.parse();
+            ctx.warn(format!("_load_from_bytes:\n\tPROTOCOL VERSION: {protocol_version}\n\tSYS_INFO: {_sys_info}\n\tLOADING TENSOR OF SIZE ({numel} * {})", storage_size(storage_type.name())));
+            let _keys = Parser::from(&mut buf).parse().unwrap();
             let mut tmp = [0; 8];
             let _ = buf.read_exact(&mut tmp);
             let to_read = u64::from_le_bytes(tmp);
@@ -124,7 +128,7 @@ fn load_from_bytes(val: Value) -> Value {
                 let x = u64::from_le_bytes(tmp);
                 tensor.push(Value::ULong(x as u128)); 
             }
-            return Value::List(tensor);
+            return Ok(Value::List(tensor));
         }
     } else {
         panic!("was not tuple");
@@ -0,0 +1,235 @@
+use std::{fmt::Display, str::FromStr};
+
+// Arbitrary precision integer for Python ints that don't fit in an i128.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    // Magnitude as little-endian base 2^32 digits, without trailing zeros.
+    digits: Vec<u32>,
+}
+
+// Largest power of 10 that fits in a digit, used for decimal conversion.
+const DECIMAL_BASE: u32 = 1_000_000_000;
+const DECIMAL_DIGITS: usize = 9;
+
+impl BigInt {
+    // Decode little-endian two's complement bytes, as written by LONG1 and LONG4.
+    pub fn from_signed_bytes_le(bytes: &[u8]) -> Self {
+        let negative = bytes.last().is_some_and(|b| b & 0x80 != 0);
+        let mut magnitude = bytes.to_vec();
+        if negative {
+            // Negate: invert and add one.
+            let mut carry = true;
+            for b in magnitude.iter_mut() {
+                let (sum, overflow) = (!*b).overflowing_add(carry as u8);
+                *b = sum;
+                carry = overflow;
+            }
+        }
+        let digits = magnitude
+            .chunks(4)
+            .map(|chunk| {
+                let mut buf = [0; 4];
+                buf[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(buf)
+            })
+            .collect();
+        Self::from_parts(negative, digits)
+    }
+
+    fn from_parts(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    // Little-endian base 2^32 digits of the absolute value.
+    pub fn magnitude(&self) -> &[u32] {
+        &self.digits
+    }
+
+    pub fn to_i128(&self) -> Option<i128> {
+        if self.digits.len() > 4 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0u128, |acc, d| (acc << 32) | *d as u128);
+        if self.negative {
+            0i128.checked_sub_unsigned(magnitude)
+        } else {
+            i128::try_from(magnitude).ok()
+        }
+    }
+
+    // Divide the magnitude in place by a small divisor, returning the remainder.
+    fn div_rem_small(digits: &mut Vec<u32>, divisor: u32) -> u32 {
+        let mut rem = 0u64;
+        for d in digits.iter_mut().rev() {
+            let cur = (rem << 32) | *d as u64;
+            *d = (cur / divisor as u64) as u32;
+            rem = cur % divisor as u64;
+        }
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        rem as u32
+    }
+
+    // Multiply the magnitude in place by a small factor and add a small term.
+    fn mul_add_small(digits: &mut Vec<u32>, factor: u32, term: u32) {
+        let mut carry = term as u64;
+        for d in digits.iter_mut() {
+            let cur = *d as u64 * factor as u64 + carry;
+            *d = cur as u32;
+            carry = cur >> 32;
+        }
+        if carry != 0 {
+            digits.push(carry as u32);
+        }
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(v: i128) -> Self {
+        let mut magnitude = v.unsigned_abs();
+        let mut digits = Vec::new();
+        while magnitude != 0 {
+            digits.push(magnitude as u32);
+            magnitude >>= 32;
+        }
+        Self::from_parts(v < 0, digits)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBigIntError;
+
+impl Display for ParseBigIntError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid decimal integer")
+    }
+}
+
+impl std::error::Error for ParseBigIntError {}
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    // Parse a decimal integer with an optional sign.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, decimal) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if decimal.is_empty() || !decimal.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseBigIntError);
+        }
+        let mut digits = Vec::new();
+        let first = decimal.len() % DECIMAL_DIGITS;
+        let chunks = std::iter::once(&decimal[..first]).chain(
+            decimal.as_bytes()[first..]
+                .chunks(DECIMAL_DIGITS)
+                .map(|c| std::str::from_utf8(c).unwrap()),
+        );
+        for chunk in chunks.filter(|c| !c.is_empty()) {
+            let factor = 10u32.pow(chunk.len() as u32);
+            Self::mul_add_small(&mut digits, factor, chunk.parse().unwrap());
+        }
+        Ok(Self::from_parts(negative, digits))
+    }
+}
+
+impl Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let mut digits = self.digits.clone();
+        let mut chunks = Vec::new();
+        while !digits.is_empty() {
+            chunks.push(Self::div_rem_small(&mut digits, DECIMAL_BASE));
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{chunk:0width$}", width = DECIMAL_DIGITS)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BigInt;
+
+    #[test]
+    fn decodes_twos_complement() {
+        assert_eq!(BigInt::from_signed_bytes_le(&[]).to_string(), "0");
+        assert_eq!(BigInt::from_signed_bytes_le(&[0xff]).to_string(), "-1");
+        assert_eq!(BigInt::from_signed_bytes_le(&[0x00, 0xff]).to_string(), "-256");
+        assert_eq!(BigInt::from_signed_bytes_le(&[0xff, 0x00]).to_string(), "255");
+        assert_eq!(BigInt::from_signed_bytes_le(&[0x00, 0x80]).to_string(), "-32768");
+
+        let mut bytes = vec![0; 25];
+        bytes.push(0x01);
+        assert_eq!(
+            BigInt::from_signed_bytes_le(&bytes).to_string(),
+            "1606938044258990275541962092341162602522202993782792835301376"
+        );
+        let mut bytes = vec![0x01];
+        bytes.extend([0; 24]);
+        bytes.push(0xff);
+        assert_eq!(
+            BigInt::from_signed_bytes_le(&bytes).to_string(),
+            "-1606938044258990275541962092341162602522202993782792835301375"
+        );
+    }
+
+    #[test]
+    fn decimal_round_trip() {
+        for s in [
+            "0",
+            "7",
+            "-1000000000",
+            "4294967296",
+            "-170141183460469231731687303715884105729",
+            "10000000000000000000000000000000000000000",
+        ] {
+            assert_eq!(s.parse::<BigInt>().unwrap().to_string(), s);
+        }
+        assert_eq!("-0".parse::<BigInt>().unwrap().to_string(), "0");
+        assert_eq!("+12".parse::<BigInt>().unwrap().to_string(), "12");
+        assert!("".parse::<BigInt>().is_err());
+        assert!("-".parse::<BigInt>().is_err());
+        assert!("12a".parse::<BigInt>().is_err());
+    }
+
+    #[test]
+    fn converts_to_i128_when_it_fits() {
+        for v in [0, -1, 1 << 40, i128::MAX, i128::MIN] {
+            assert_eq!(BigInt::from(v).to_i128(), Some(v));
+            assert_eq!(BigInt::from(v).to_string(), v.to_string());
+        }
+        let too_big = "170141183460469231731687303715884105728".parse::<BigInt>().unwrap();
+        assert_eq!(too_big.to_i128(), None);
+    }
+}
@@ -0,0 +1,169 @@
+// Decoders for the text encodings used by protocol 0 string opcodes.
+// https://github.com/python/cpython/blob/3.12/Objects/bytesobject.c (_PyBytes_DecodeEscape)
+// https://github.com/python/cpython/blob/3.12/Objects/unicodeobject.c (raw_unicode_escape)
+
+use crate::error::PickleError;
+
+// Python's `codecs.escape_decode`, used to read the repr written by STRING.
+pub fn escape_decode(data: &[u8]) -> Result<Vec<u8>, PickleError> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let c = data[i];
+        i += 1;
+        if c != b'\\' {
+            out.push(c);
+            continue;
+        }
+        let Some(&c) = data.get(i) else {
+            return Err(PickleError::Malformed("trailing \\ in string".to_string()));
+        };
+        i += 1;
+        match c {
+            // Escaped newline is a line continuation.
+            b'\n' => {}
+            b'\\' | b'\'' | b'"' => out.push(c),
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b't' => out.push(b'\t'),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b'v' => out.push(0x0b),
+            b'0'..=b'7' => {
+                let mut value = (c - b'0') as u32;
+                for _ in 0..2 {
+                    match data.get(i) {
+                        Some(&d @ b'0'..=b'7') => {
+                            value = value * 8 + (d - b'0') as u32;
+                            i += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                // CPython truncates octal escapes above \377.
+                out.push(value as u8);
+            }
+            b'x' => {
+                let value = data
+                    .get(i..i + 2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| {
+                        PickleError::Malformed(format!("invalid \\x escape at position {}", i - 2))
+                    })?;
+                out.push(value);
+                i += 2;
+            }
+            // Unknown escapes are kept verbatim.
+            _ => {
+                out.push(b'\\');
+                out.push(c);
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Python's `raw_unicode_escape` codec, used by UNICODE. Bytes are
+// latin-1 except for \uXXXX and \UXXXXXXXX escapes, which only count
+// when preceded by an odd number of backslashes.
+pub fn raw_unicode_escape_decode(data: &[u8]) -> Result<String, PickleError> {
+    let mut out = String::with_capacity(data.len());
+    // High surrogate waiting for its pair, as written by narrow Python 2 builds.
+    let mut high: Option<u32> = None;
+    let mut i = 0;
+    while i < data.len() {
+        let start = i;
+        while i < data.len() && data[i] == b'\\' {
+            i += 1;
+        }
+        let backslashes = i - start;
+        let escape = match data.get(i) {
+            Some(b'u') if backslashes % 2 == 1 => 4,
+            Some(b'U') if backslashes % 2 == 1 => 8,
+            _ => 0,
+        };
+        if escape == 0 || backslashes > 1 {
+            if let Some(h) = high {
+                return Err(invalid_code_point(h));
+            }
+        }
+        if escape == 0 {
+            if backslashes == 0 {
+                out.push(data[i] as char);
+                i += 1;
+            } else {
+                out.extend(std::iter::repeat_n('\\', backslashes));
+            }
+            continue;
+        }
+        out.extend(std::iter::repeat_n('\\', backslashes - 1));
+        i += 1;
+        let code = data
+            .get(i..i + escape)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| {
+                PickleError::Malformed(format!("truncated \\uXXXX escape at position {}", i - 2))
+            })?;
+        i += escape;
+        let code = match (high.take(), code) {
+            (Some(h), 0xDC00..=0xDFFF) => 0x10000 + ((h - 0xD800) << 10) + (code - 0xDC00),
+            (Some(h), _) => return Err(invalid_code_point(h)),
+            (None, 0xD800..=0xDBFF) => {
+                high = Some(code);
+                continue;
+            }
+            (None, _) => code,
+        };
+        out.push(char::from_u32(code).ok_or_else(|| invalid_code_point(code))?);
+    }
+    if let Some(h) = high {
+        return Err(invalid_code_point(h));
+    }
+    Ok(out)
+}
+
+// Lone surrogates and values past U+10FFFF can't be represented in a Rust String.
+fn invalid_code_point(code: u32) -> PickleError {
+    PickleError::Malformed(format!("invalid code point \\U{code:08x} in unicode string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_decode_matches_cpython() {
+        assert_eq!(escape_decode(b"plain").unwrap(), b"plain");
+        assert_eq!(escape_decode(br#"a\'b\"c\\d"#).unwrap(), b"a'b\"c\\d");
+        assert_eq!(escape_decode(br"\a\b\f\n\r\t\v").unwrap(), b"\x07\x08\x0c\n\r\t\x0b");
+        assert_eq!(escape_decode(br"\x41\xff\x00").unwrap(), b"A\xff\x00");
+        assert_eq!(escape_decode(br"\101\0\12\1234\777").unwrap(), b"A\x00\nS4\xff");
+        assert_eq!(escape_decode(b"a\\\nb").unwrap(), b"ab");
+        assert_eq!(escape_decode(b"\\q\xe1").unwrap(), b"\\q\xe1");
+        assert!(escape_decode(br"\x4").is_err());
+        assert!(escape_decode(br"\xzz").is_err());
+        assert!(escape_decode(b"abc\\").is_err());
+    }
+
+    #[test]
+    fn raw_unicode_escape_matches_cpython() {
+        assert_eq!(raw_unicode_escape_decode(b"h\xe9llo").unwrap(), "héllo");
+        assert_eq!(raw_unicode_escape_decode(br"\u20ac\U0001f600").unwrap(), "€😀");
+        assert_eq!(raw_unicode_escape_decode(br"\\\u000a").unwrap(), "\\\\\n");
+        // Escaped backslashes are not read as the start of another escape.
+        assert_eq!(raw_unicode_escape_decode(br"\u005cu0041").unwrap(), r"\u0041");
+        // Even number of backslashes: no escape.
+        assert_eq!(raw_unicode_escape_decode(br"\\u20ac").unwrap(), r"\\u20ac");
+        assert_eq!(raw_unicode_escape_decode(b"\\\\\xe9").unwrap(), r"\\é");
+        // Other escapes are not interpreted.
+        assert_eq!(raw_unicode_escape_decode(br"\n\x41").unwrap(), r"\n\x41");
+        // Surrogate pairs from narrow Python 2 builds are combined.
+        assert_eq!(raw_unicode_escape_decode(br"\ud83d\ude00").unwrap(), "😀");
+        assert!(raw_unicode_escape_decode(br"\ud83d").is_err());
+        assert!(raw_unicode_escape_decode(br"\u12").is_err());
+        assert!(raw_unicode_escape_decode(br"\U00110000").is_err());
+    }
+}
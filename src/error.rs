@@ -0,0 +1,151 @@
+use std::fmt::Display;
+use std::string::FromUtf8Error;
+
+use crate::op::Op;
+
+#[derive(Debug)]
+pub enum PickleError {
+    // Stream ended before an opcode or its argument was complete.
+    UnexpectedEof,
+    // Underlying reader failed for reasons other than EOF.
+    Io(std::io::Error),
+    // Byte does not map to any opcode in the pickle table.
+    UnknownOpcode(u8),
+    // Opcode exists but the VM can't execute it.
+    UnsupportedOpcode(Op),
+    // PROTO declared a version we don't know about.
+    UnsupportedProtocol(u8),
+    // Tried to pop from an empty stack or past the last mark.
+    StackUnderflow,
+    // STOP reached with marks that were never popped.
+    UnbalancedMarks(usize),
+    // GET-like opcode referenced a memo slot that was never set.
+    BadMemoIndex(usize),
+    // String argument was not valid UTF-8.
+    InvalidUtf8(FromUtf8Error),
+    // Value on the stack is not what the opcode operates on.
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    // EXT opcode used a code missing from the copyreg registry.
+    UnregisteredExtension(i32),
+    // Global rejected by the parser's SafetyPolicy.
+    ForbiddenGlobal { module: String, name: String },
+    // NEXT_BUFFER ran past the out-of-band buffers given to the parser.
+    MissingBuffer,
+    // Object graph refers back to itself, so it has no tree form.
+    Cycle,
+    // Opcode argument could not be decoded.
+    Malformed(String),
+    // Stream asked for more than the configured limit allows.
+    LimitExceeded {
+        limit: &'static str,
+        value: u64,
+        max: u64,
+    },
+    // Any of the above, along with where in the stream it happened.
+    Context {
+        // Absolute byte offset of the failing opcode.
+        offset: u64,
+        // Opcode being executed, if it could be decoded.
+        op: Option<Op>,
+        // Top of the stack as the failing opcode found it, topmost first.
+        stack: Vec<String>,
+        source: Box<PickleError>,
+    },
+}
+
+impl PickleError {
+    // The underlying error, without any decoding context.
+    pub fn kind(&self) -> &PickleError {
+        match self {
+            PickleError::Context { source, .. } => source.kind(),
+            e => e,
+        }
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            PickleError::Context { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    pub fn op(&self) -> Option<&Op> {
+        match self {
+            PickleError::Context { op, .. } => op.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PickleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PickleError::UnexpectedEof => write!(f, "unexpected end of pickle stream"),
+            PickleError::Io(e) => write!(f, "error reading pickle stream: {e}"),
+            PickleError::UnknownOpcode(byte) => write!(f, "unknown opcode 0x{byte:02x}"),
+            PickleError::UnsupportedOpcode(op) => write!(f, "unsupported opcode {op:?}"),
+            PickleError::UnsupportedProtocol(v) => write!(f, "unsupported pickle protocol {v}"),
+            PickleError::StackUnderflow => write!(f, "stack underflow"),
+            PickleError::UnbalancedMarks(n) => write!(f, "{n} unbalanced mark(s) at STOP"),
+            PickleError::BadMemoIndex(idx) => write!(f, "memo index {idx} was never set"),
+            PickleError::InvalidUtf8(e) => write!(f, "invalid utf-8 string: {e}"),
+            PickleError::TypeMismatch { expected, found } => {
+                write!(f, "expected {expected} on the stack, found {found}")
+            }
+            PickleError::UnregisteredExtension(code) => {
+                write!(f, "unregistered extension code {code}")
+            }
+            PickleError::ForbiddenGlobal { module, name } => {
+                write!(f, "global {module}.{name} is forbidden by the safety policy")
+            }
+            PickleError::MissingBuffer => write!(f, "not enough out-of-band buffers"),
+            PickleError::Cycle => write!(f, "value contains a reference cycle"),
+            PickleError::Malformed(reason) => write!(f, "malformed pickle: {reason}"),
+            PickleError::LimitExceeded { limit, value, max } => {
+                write!(f, "{limit} of {value} exceeds the limit of {max}")
+            }
+            PickleError::Context {
+                offset,
+                op,
+                stack,
+                source,
+            } => {
+                write!(f, "{source} at offset {offset} (0x{offset:x})")?;
+                if let Some(op) = op {
+                    write!(f, " in {op:?}")?;
+                }
+                write!(f, ", stack top: [{}]", stack.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PickleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PickleError::Io(e) => Some(e),
+            PickleError::InvalidUtf8(e) => Some(e),
+            PickleError::Context { source, .. } => source.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PickleError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            PickleError::UnexpectedEof
+        } else {
+            PickleError::Io(e)
+        }
+    }
+}
+
+impl From<FromUtf8Error> for PickleError {
+    fn from(e: FromUtf8Error) -> Self {
+        PickleError::InvalidUtf8(e)
+    }
+}
@@ -1,38 +1,127 @@
+use std::borrow::Cow;
 use std::io::Read;
 
-use value::Value;
-use vm::{Extension, VM};
+pub use error::PickleError;
+use policy::SafetyPolicy;
+use value::{Graph, Value};
+use vm::VM;
+pub use vm::{ExtensionContext, Limits};
 
-mod op;
+pub mod bigint;
+mod codec;
+pub mod error;
+pub mod op;
+pub mod policy;
+pub mod scan;
 pub mod value;
 mod vm;
+mod zip;
 
-pub struct Parser<'a> {
-    vm: VM<'a>,
+// Reads from a reader borrowed for `'a`. Values borrow the out-of-band
+// buffers given to `set_buffers` for `'b`.
+pub struct Parser<'a, 'b> {
+    vm: VM<'a, 'b>,
 }
 
-impl<'a> Parser<'a> {
+impl<'a, 'b> Parser<'a, 'b> {
     pub fn from(buf: &'a mut dyn Read) -> Self {
         Self { vm: VM::from(buf) }
     }
 
+    // Parse another stream with the same extensions and settings.
     pub fn load(&mut self, buf: &'a mut dyn Read) {
-        self.vm = VM::from(buf);
+        self.vm.reset(buf);
     }
 
-    pub fn add_extension(&mut self, module: &str, name:&str, ext: Extension) {
-        self.vm.load_extension(module, name, ext);
+    // Reject any length prefixed argument (bytes, strings, ints)
+    // longer than `max_length` before allocating for it.
+    // Shorthand for `set_limits` with only `Limits::max_length`
+    // changed, so a later `set_limits` replaces it.
+    pub fn set_max_length(&mut self, max_length: u64) {
+        self.set_limits(Limits { max_length, ..self.limits() });
     }
 
-    pub fn parse(&mut self) -> Value {
+    // Bound the resources a stream can make the parser use. Kept
+    // when loading another stream.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.vm.set_limits(limits);
+    }
+
+    // The limits in use, to change only some of them.
+    pub fn limits(&self) -> Limits {
+        self.vm.limits()
+    }
+
+    // Replace calls to `module.name` with `ext`, which gets the call
+    // arguments and returns the resulting value. Calls are made by
+    // REDUCE, INST, OBJ and NEWOBJ_EX, while NEWOBJ only keeps its args.
+    pub fn add_extension<F>(&mut self, module: &str, name: &str, ext: F)
+    where
+        F: FnMut(&mut ExtensionContext<'_, 'b>, Value<'b>) -> Result<Value<'b>, PickleError> + 'a,
+    {
+        self.vm.load_extension(module, name, Box::new(ext));
+    }
+
+    // Register an entry of the copyreg extension registry, as done by
+    // `copyreg.add_extension(module, name, code)` on the pickling side.
+    // EXT1, EXT2 and EXT4 resolve codes through these entries.
+    pub fn add_extension_code(&mut self, code: u32, module: &str, name: &str) {
+        self.vm.add_extension_code(code, module, name);
+    }
+
+    // Resolve the pids of PERSID and BINPERSID, like overriding
+    // `Unpickler.persistent_load`. Without a hook they are parsed
+    // as `Value::PersistentId`.
+    pub fn set_persistent_load<F>(&mut self, f: F)
+    where
+        F: FnMut(Value<'b>) -> Result<Value<'b>, PickleError> + 'a,
+    {
+        self.vm.set_persistent_load(Box::new(f));
+    }
+
+    // Out-of-band buffers for a protocol 5 stream, in the order they were
+    // passed to `buffer_callback`. Each NEXT_BUFFER takes the next one.
+    // Borrowed buffers aren't copied, the values borrow them.
+    pub fn set_buffers<I>(&mut self, buffers: I)
+    where
+        I: IntoIterator,
+        I::Item: Into<Cow<'b, [u8]>>,
+    {
+        self.vm.set_buffers(buffers.into_iter().map(Into::into).collect());
+    }
+
+    // Globals the stream may reference. Defaults to `SafetyPolicy::preset`,
+    // which rejects well known ways to run code.
+    pub fn set_policy(&mut self, policy: SafetyPolicy) {
+        self.vm.set_policy(policy);
+    }
+
+    // Warnings emitted by extensions during the last parse.
+    pub fn warnings(&self) -> &[String] {
+        self.vm.warnings()
+    }
+
+    pub fn parse(&mut self) -> Result<Value<'b>, PickleError> {
         loop {
-            if !self.vm.step() {
-                return self.vm.result().expect("did not expect an error tbh");
+            if !self.vm.step()? {
+                return self.vm.result();
             }
         }
     }
+
+    // Parse the stream as an object graph: memoized lists, dicts, sets
+    // and objects are kept once and referenced by `Value::Ref`, so values
+    // referenced more than once stay one value and cycles survive.
+    // `Graph::materialize` turns the result into a tree.
+    pub fn parse_graph(&mut self) -> Result<Graph<'b>, PickleError> {
+        self.vm.set_object_graph(true);
+        let root = self.parse();
+        self.vm.set_object_graph(false);
+        Ok(self.vm.take_graph(root?))
+    }
 }
 
+#[cfg(test)]
 mod tests {
     #[test]
     fn it_works() {
@@ -52,7 +141,1057 @@ mod tests {
         ];
 
         let mut reader = BufReader::new(&file[..]);
-        let result = Parser::from(&mut reader).parse();
+        let result = Parser::from(&mut reader).parse().unwrap();
         assert_eq!(result.to_string(), "(1, 2, 3, 4, (5, 6, 7), 'Test', ('This is just a test.', [2, 4, 6, 8]), 'One', 'Two', 'Three')");
     }
+
+    fn parse(mut bytes: &[u8]) -> Result<crate::value::Value<'static>, crate::PickleError> {
+        crate::Parser::from(&mut bytes).parse()
+    }
+
+    fn parse_err(bytes: &[u8]) -> crate::PickleError {
+        match parse(bytes).unwrap_err() {
+            crate::PickleError::Context { source, .. } => *source,
+            e => panic!("error without context: {e}"),
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        use crate::PickleError;
+
+        assert!(matches!(parse_err(b""), PickleError::UnexpectedEof));
+        assert!(matches!(parse_err(b"\x80\x09."), PickleError::UnsupportedProtocol(9)));
+        // Truncated BININT argument.
+        assert!(matches!(parse_err(b"\x80\x02J\x01\x02"), PickleError::UnexpectedEof));
+        // Missing STOP.
+        assert!(matches!(parse_err(b"\x80\x02K\x01"), PickleError::UnexpectedEof));
+        assert!(matches!(parse_err(b"\x80\x02\xff."), PickleError::UnknownOpcode(0xff)));
+        assert!(matches!(parse_err(b"\x80\x02\x85."), PickleError::StackUnderflow));
+        assert!(matches!(parse_err(b"\x80\x02h\x05."), PickleError::BadMemoIndex(5)));
+        assert!(matches!(
+            parse_err(b"\x80\x03\x8c\x01\xff."),
+            PickleError::InvalidUtf8(_)
+        ));
+        assert!(matches!(
+            parse_err(b"\x80\x02K\x01K\x02a."),
+            PickleError::TypeMismatch { expected: "list", found: "int" }
+        ));
+        // STOP on an empty stack has nothing to return.
+        assert!(matches!(parse(b"\x80\x02."), Err(PickleError::StackUnderflow)));
+    }
+
+    #[test]
+    fn errors_carry_offset_opcode_and_stack() {
+        use crate::op::Op;
+
+        // APPEND onto an int at offset 6.
+        let err = parse(b"\x80\x02K\x01K\x02a.").unwrap_err();
+        assert_eq!(err.offset(), Some(6));
+        assert_eq!(err.op(), Some(&Op::Append));
+        let crate::PickleError::Context { stack, .. } = &err else {
+            panic!("missing context");
+        };
+        // The snapshot is the stack APPEND found, not what was left of it.
+        assert_eq!(stack, &["2", "1"]);
+
+        // TUPLE3 with only two values shows both of them.
+        let err = parse(b"\x80\x02K\x01K\x02\x87.").unwrap_err();
+        let crate::PickleError::Context { stack, source, .. } = &err else {
+            panic!("missing context");
+        };
+        assert_eq!(stack, &["2", "1"]);
+        assert!(matches!(**source, crate::PickleError::StackUnderflow));
+
+        // Unknown opcode inside a frame: offset is absolute, not frame-relative.
+        let err = parse(b"\x80\x04\x95\x04\x00\x00\x00\x00\x00\x00\x00K\x01\xff.").unwrap_err();
+        assert_eq!(err.offset(), Some(13));
+        assert_eq!(err.op(), None);
+        assert!(err.to_string().contains("at offset 13 (0xd)"));
+    }
+
+    #[test]
+    fn protocol_0_and_1_without_proto_header() {
+        assert_eq!(parse(b"N.").unwrap().to_string(), "None");
+        assert_eq!(parse(b"]q\x00(K\x01K\x02e.").unwrap().to_string(), "[1, 2]");
+        assert_eq!(
+            parse(b"(K\x01X\x01\x00\x00\x00aq\x00tq\x01.").unwrap().to_string(),
+            "(1, 'a')"
+        );
+        // The peeked byte still counts towards offsets.
+        assert_eq!(parse(b"K\x01\xff.").unwrap_err().offset(), Some(2));
+    }
+
+    #[test]
+    fn text_mode_numbers() {
+        use crate::value::Value;
+
+        let result = parse(
+            b"(I1\nI-5\nI01\nI00\nL1099511627776L\nL-1180591620717411303424L\nF1.5\nFinf\nF-0.0\nt.",
+        )
+        .unwrap();
+        assert_eq!(
+            result.to_string(),
+            "(1, -5, True, False, 1099511627776, -1180591620717411303424, 1.5, inf, -0.0)"
+        );
+        // Python 2 writes big ints with INT on 64-bit platforms, and longs
+        // without a trailing L are accepted as well.
+        assert_eq!(parse(b"I1099511627776\n.").unwrap(), Value::Long(1099511627776));
+        assert_eq!(parse(b"L42\n.").unwrap(), Value::Long(42));
+        assert_eq!(parse(b"\x80\x02J\x90\xee\xfe\xff.").unwrap(), Value::Int(-70000));
+        assert!(matches!(
+            parse_err(b"I1.5\n."),
+            crate::PickleError::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn text_mode_strings() {
+        use crate::value::Value;
+
+        // Python 2 str reprs, with either quote style.
+        assert_eq!(parse(b"S'abc'\n.").unwrap(), Value::String("abc".to_string()));
+        assert_eq!(
+            parse(b"S\"it's\"\n.").unwrap(),
+            Value::String("it's".to_string())
+        );
+        assert_eq!(
+            parse(b"S'a\\'b\\\\c\\n\\x41\\101\\t'\n.").unwrap(),
+            Value::String("a'b\\c\nAA\t".to_string())
+        );
+        // Non utf-8 Python 2 strings can only be bytes.
+        assert_eq!(
+            parse(b"S'\\xff\\x00'\n.").unwrap(),
+            Value::Bytes(vec![0xff, 0x00])
+        );
+        assert_eq!(
+            parse(b"\x80\x02U\x02\xff\x00.").unwrap(),
+            Value::Bytes(vec![0xff, 0x00])
+        );
+        assert_eq!(
+            parse(b"\x80\x02T\x02\x00\x00\x00hi.").unwrap(),
+            Value::String("hi".to_string())
+        );
+        for unquoted in [&b"S'abc\n."[..], b"Sabc\n.", b"S'abc\"\n.", b"S'\n."] {
+            assert!(matches!(
+                parse_err(unquoted),
+                crate::PickleError::Malformed(_)
+            ));
+        }
+
+        // pickle.dumps('h\xe9\\llo\n€\U0001f600\0', 0) without the PUT.
+        assert_eq!(
+            parse(b"Vh\xe9\\u005cllo\\u000a\\u20ac\\U0001f600\\u0000\n.").unwrap(),
+            Value::String("h\u{e9}\\llo\n\u{20ac}\u{1f600}\0".to_string())
+        );
+        assert_eq!(parse(b"V\n.").unwrap(), Value::String(String::new()));
+    }
+
+    #[test]
+    fn long_binget_past_255() {
+        use crate::op::*;
+        use crate::value::Value;
+
+        // Memoize 300 strings with LONG_BINPUT, then read every one
+        // back with LONG_BINGET, as pickle does past the first 256.
+        let mut file = vec![PROTO, 2, MARK];
+        for i in 0..300u32 {
+            let s = format!("s{i}");
+            file.extend([SHORT_BINUNICODE, s.len() as u8]);
+            file.extend(s.as_bytes());
+            file.push(LONG_BINPUT);
+            file.extend(i.to_le_bytes());
+        }
+        for i in (0..300u32).rev() {
+            file.push(LONG_BINGET);
+            file.extend(i.to_le_bytes());
+        }
+        file.extend([TUPLE, STOP]);
+
+        let Value::Tuple(items) = parse(&file).unwrap() else {
+            panic!("expected a tuple");
+        };
+        assert_eq!(items.len(), 600);
+        assert_eq!(items[299], Value::String("s299".to_string()));
+        assert_eq!(items[300], Value::String("s299".to_string()));
+        assert_eq!(items[599], Value::String("s0".to_string()));
+
+        let mut missing = vec![PROTO, 2, LONG_BINGET];
+        missing.extend(256u32.to_le_bytes());
+        missing.push(STOP);
+        assert!(matches!(
+            parse_err(&missing),
+            crate::PickleError::BadMemoIndex(256)
+        ));
+    }
+
+    #[test]
+    fn sparse_and_out_of_order_memo() {
+        // BINPUT 5, BINPUT 2, then read both back in reverse.
+        assert_eq!(
+            parse(b"\x80\x02(K\x01q\x05K\x02q\x02h\x02h\x05t.").unwrap().to_string(),
+            "(1, 2, 2, 1)"
+        );
+        // A later PUT to the same index overwrites the previous value.
+        assert_eq!(
+            parse(b"\x80\x02(K\x01q\x00K\x02q\x00h\x00t.").unwrap().to_string(),
+            "(1, 2, 2)"
+        );
+        // MEMOIZE uses the memo size, so after BINPUT 7 it stores at 1.
+        assert_eq!(
+            parse(b"\x80\x04(K\x01q\x07K\x02\x94h\x01h\x07t.").unwrap().to_string(),
+            "(1, 2, 2, 1)"
+        );
+        assert!(matches!(
+            parse_err(b"\x80\x02K\x01q\x05h\x04."),
+            crate::PickleError::BadMemoIndex(4)
+        ));
+    }
+
+    #[test]
+    fn text_mode_memo() {
+        // Shared list written with PUT and read back with GET.
+        assert_eq!(
+            parse(b"(]Vx\np0\nap1\ng1\ng0\nt.").unwrap().to_string(),
+            "(['x'], ['x'], 'x')"
+        );
+        // pickle.dumps((1, -5, True, False, 2**40), 0)
+        assert_eq!(
+            parse(b"(I1\nI-5\nI01\nI00\nL1099511627776L\ntp0\n.").unwrap().to_string(),
+            "(1, -5, True, False, 1099511627776)"
+        );
+        // Text and binary memo opcodes share the same memo.
+        assert_eq!(
+            parse(b"\x80\x02X\x01\x00\x00\x00ap0\nh\x00g0\n\x87.").unwrap().to_string(),
+            "('a', 'a', 'a')"
+        );
+        assert!(matches!(
+            parse_err(b"Vx\ng7\n."),
+            crate::PickleError::BadMemoIndex(7)
+        ));
+        assert!(matches!(
+            parse_err(b"Vx\npx\n."),
+            crate::PickleError::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn sets_and_frozensets() {
+        use crate::value::Value;
+
+        // ({3, 1, 2}, frozenset({'b', 'a'}), set(), frozenset())
+        let file = b"\x80\x04\x95\x1e\x00\x00\x00\x00\x00\x00\x00(\x8f\x94(K\x01K\x02K\x03\x90(\x8c\x01b\x94\x8c\x01a\x94\x91\x94\x8f\x94(\x91\x94t\x94.";
+        assert_eq!(
+            parse(file).unwrap().to_string(),
+            "({1, 2, 3}, frozenset({'a', 'b'}), set(), frozenset())"
+        );
+
+        // {frozenset({1}): 'x'}
+        let result = parse(b"\x80\x04}((K\x01\x91\x8c\x01xu.").unwrap();
+        let Value::Dict(dict) = &result else {
+            panic!("expected a dict");
+        };
+        let key = Value::FrozenSet([Value::UInt(1)].into_iter().collect());
+        assert_eq!(dict[&key], Value::String("x".to_string()));
+        assert_eq!(result.to_string(), "{frozenset({1}): 'x'}");
+
+        assert!(matches!(
+            parse_err(b"\x80\x04](K\x01\x90."),
+            crate::PickleError::TypeMismatch { expected: "set", found: "list" }
+        ));
+    }
+
+    #[test]
+    fn mark_based_and_stack_opcodes() {
+        // pickle.dumps({'a': [1, 2], 'b': (3,)}, 0)
+        assert_eq!(
+            parse(b"(dp0\nVa\np1\n(lp2\nI1\naI2\nasVb\np3\n(I3\ntp4\ns.").unwrap().to_string(),
+            "{'a': [1, 2], 'b': (3)}"
+        );
+        // pickle.dumps([{}, {'k': 1}], 1)
+        assert_eq!(
+            parse(b"]q\x00(}q\x01}q\x02X\x01\x00\x00\x00kq\x03K\x01se.").unwrap().to_string(),
+            "[{}, {'k': 1}]"
+        );
+        assert_eq!(
+            parse(b"(K\x01K\x02K\x03d.").unwrap_err().kind().to_string(),
+            "malformed pickle: odd number of items for a dict"
+        );
+        // DUP copies the top of the stack.
+        assert_eq!(parse(b"K\x012\x86.").unwrap().to_string(), "(1, 1)");
+        // POP drops the top value, or the mark if nothing is above it.
+        assert_eq!(parse(b"K\x01K\x020.").unwrap().to_string(), "1");
+        assert_eq!(parse(b"(K\x01(0t.").unwrap().to_string(), "(1)");
+        // POP_MARK drops everything up to and including the mark.
+        assert_eq!(parse(b"K\x01(K\x02K\x031.").unwrap().to_string(), "1");
+        assert!(matches!(parse_err(b"K\x011."), crate::PickleError::StackUnderflow));
+        assert!(matches!(parse_err(b"0."), crate::PickleError::StackUnderflow));
+        assert!(matches!(
+            parse_err(b"]K\x01K\x02s."),
+            crate::PickleError::TypeMismatch { expected: "dict", found: "list" }
+        ));
+    }
+
+    #[test]
+    fn marks_live_outside_the_value_stack() {
+        use crate::PickleError;
+
+        // Values below a mark can't be reached until it is popped.
+        assert!(matches!(parse_err(b"K\x01(\x85."), PickleError::StackUnderflow));
+        assert!(matches!(parse_err(b"](K\x01a."), PickleError::StackUnderflow));
+        assert!(matches!(parse(b"K\x01(."), Err(PickleError::UnbalancedMarks(1))));
+        assert!(matches!(parse(b"K\x01((K\x02."), Err(PickleError::UnbalancedMarks(2))));
+        assert!(matches!(parse_err(b"t."), PickleError::StackUnderflow));
+        // Nested marks.
+        assert_eq!(parse(b"((K\x01t(t](e\x86t.").unwrap().to_string(), "((1), ((), []))");
+    }
+
+    #[test]
+    fn inst_and_obj() {
+        use crate::value::Value;
+        use crate::Parser;
+
+        // Python 2 old-style instance: INST with args, then BUILD.
+        let inst = parse(b"(I1\nS'a'\ni__main__\nDog\np0\n(dp1\nS'name'\np2\nS'bob'\np3\nsb.")
+            .unwrap()
+            .as_instance()
+            .unwrap();
+        assert_eq!(inst.as_key(), "__main__.Dog");
+        assert_eq!(inst.args, vec![Value::Int(1), Value::String("a".to_string())]);
+        assert_eq!(inst.fields_to_string(), "name: 'bob'");
+
+        // OBJ takes the class from the marked items.
+        let inst = parse(b"\x80\x01(c__main__\nDog\nK\x01K\x02o.")
+            .unwrap()
+            .as_instance()
+            .unwrap();
+        assert_eq!(inst.as_key(), "__main__.Dog");
+        assert_eq!(inst.args, vec![Value::UInt(1), Value::UInt(2)]);
+
+        // Both route through extensions.
+        fn args_as_list<'b>(_: &mut crate::ExtensionContext<'_, 'b>, args: Value<'b>) -> Result<Value<'b>, crate::PickleError> {
+            Ok(Value::List(args.as_tuple().unwrap()))
+        }
+        for file in [&b"(I1\ni__main__\nDog\n."[..], b"(c__main__\nDog\nI1\no."] {
+            let mut reader = file;
+            let mut parser = Parser::from(&mut reader);
+            parser.add_extension("__main__", "Dog", args_as_list);
+            assert_eq!(parser.parse().unwrap(), Value::List(vec![Value::Int(1)]));
+        }
+
+        // NEWOBJ doesn't, it only keeps the args of `cls.__new__`.
+        let mut reader = &b"\x80\x02c__main__\nDog\nK\x01\x85\x81."[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.add_extension("__main__", "Dog", args_as_list);
+        let inst = parser.parse().unwrap().as_instance().unwrap();
+        assert_eq!(inst.args, vec![Value::UInt(1)]);
+
+        assert!(matches!(parse_err(b"(o."), crate::PickleError::StackUnderflow));
+        assert!(matches!(
+            parse_err(b"(K\x01o."),
+            crate::PickleError::TypeMismatch { expected: "class", found: "int" }
+        ));
+    }
+
+    #[test]
+    fn newobj_ex_keyword_arguments() {
+        use crate::value::Value;
+        use crate::Parser;
+
+        // P(1, b='x') with __getnewargs_ex__ returning ((1,), {'b': 'x'}).
+        let file = b"\x80\x04\x95#\x00\x00\x00\x00\x00\x00\x00\x8c\x08__main__\x94\x8c\x01P\x94\x93\x94K\x01\x85\x94}\x94\x8c\x01b\x94\x8c\x01x\x94s\x92\x94.";
+        let inst = parse(file).unwrap().as_instance().unwrap();
+        assert_eq!(inst.as_key(), "__main__.P");
+        assert_eq!(inst.args, vec![Value::UInt(1)]);
+        let kwargs = inst.kwargs.unwrap();
+        assert_eq!(kwargs.len(), 1);
+        assert_eq!(kwargs["b"], Value::String("x".to_string()));
+
+        // Extensions get the args tuple, as for REDUCE, and the keyword
+        // arguments from their context.
+        fn args_and_kwargs<'b>(ctx: &mut crate::ExtensionContext<'_, 'b>, val: Value<'b>) -> Result<Value<'b>, crate::PickleError> {
+            let kwargs = ctx.kwargs().unwrap().iter();
+            let kwargs = kwargs.map(|(k, v)| (Value::String(k.clone()), v.clone())).collect();
+            Ok(Value::Tuple(vec![val, Value::Dict(kwargs)]))
+        }
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.add_extension("__main__", "P", args_and_kwargs);
+        assert_eq!(parser.parse().unwrap().to_string(), "((1), {'b': 'x'})");
+
+        // Keyword names must be strings.
+        assert!(matches!(
+            parse_err(b"\x80\x04c__main__\nP\n)}K\x01K\x02s\x92."),
+            crate::PickleError::TypeMismatch { expected: "str keyword", found: "int" }
+        ));
+    }
+
+    #[test]
+    fn build_states() {
+        use crate::value::Value;
+
+        // __slots__ only: (None, slots).
+        let inst = parse(b"\x80\x02c__main__\nS\nq\x00)\x81q\x01N}q\x02(X\x01\x00\x00\x00xq\x03K\x01X\x01\x00\x00\x00yq\x04K\x02u\x86q\x05b.")
+            .unwrap()
+            .as_instance()
+            .unwrap();
+        assert!(inst.fields().is_empty());
+        assert_eq!(inst.slots_to_string(), "x: 1, y: 2");
+
+        // __slots__ and __dict__: (dict, slots).
+        let inst = parse(b"\x80\x02c__main__\nW\nq\x00)\x81q\x01}q\x02X\x01\x00\x00\x00zq\x03K\x03s}q\x04X\x01\x00\x00\x00xq\x05K\x01s\x86q\x06b.")
+            .unwrap()
+            .as_instance()
+            .unwrap();
+        assert_eq!(inst.fields_to_string(), "z: 3");
+        assert_eq!(inst.slots_to_string(), "x: 1");
+        assert_eq!(inst.state(), None);
+
+        // Arbitrary __getstate__ result is kept for __setstate__.
+        let inst = parse(b"\x80\x02c__main__\nC\nq\x00)\x81q\x01]q\x02(K\x01K\x02eb.")
+            .unwrap()
+            .as_instance()
+            .unwrap();
+        assert!(inst.fields().is_empty());
+        assert_eq!(inst.state(), Some(&Value::List(vec![Value::UInt(1), Value::UInt(2)])));
+
+        // BUILD also applies to REDUCE results.
+        let Value::Callable(inst, args) = parse(b"\x80\x02c__main__\nf\nK\x01\x85R}X\x01\x00\x00\x00aK\x02sb.").unwrap() else {
+            panic!("expected a callable");
+        };
+        assert_eq!(inst.fields_to_string(), "a: 2");
+        assert_eq!(*args, Value::Tuple(vec![Value::UInt(1)]));
+
+        // __dict__ takes keys of any type, setattr only str names.
+        let inst = parse(b"\x80\x02c__main__\nC\n)\x81}(X\x01\x00\x00\x00aK\x03K\x01K\x02ub.")
+            .unwrap()
+            .as_instance()
+            .unwrap();
+        assert_eq!(inst.fields_to_string(), "a: 3, 1: 2");
+        assert_eq!(inst.other_fields()[&Value::UInt(1)], Value::UInt(2));
+        assert!(matches!(
+            parse_err(b"\x80\x02c__main__\nS\n)\x81N}K\x01K\x02s\x86b."),
+            crate::PickleError::TypeMismatch { expected: "str attribute name", found: "int" }
+        ));
+        assert!(matches!(
+            parse_err(b"\x80\x02K\x01}b."),
+            crate::PickleError::TypeMismatch { expected: "object", found: "int" }
+        ));
+    }
+
+    #[test]
+    fn long1_and_long4() {
+        use crate::value::Value;
+
+        // Small LONG1 values are sign extended.
+        assert_eq!(parse(b"\x80\x02\x8a\x01\xff.").unwrap(), Value::Long(-1));
+        assert_eq!(parse(b"\x80\x02\x8a\x02\x00\xff.").unwrap(), Value::Long(-256));
+        assert_eq!(parse(b"\x80\x02\x8a\x02\xff\x00.").unwrap(), Value::Long(255));
+        assert_eq!(parse(b"\x80\x02\x8a\x00.").unwrap(), Value::Long(0));
+        assert_eq!(
+            parse(b"\x80\x02\x8a\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80.").unwrap(),
+            Value::Long(i128::MIN)
+        );
+        // 2**127 needs 17 bytes.
+        let result = parse(b"\x80\x02\x8a\x11\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x00.").unwrap();
+        assert_eq!(result.type_name(), "int");
+        assert_eq!(result.to_string(), "170141183460469231731687303715884105728");
+
+        // -(2**200) + 1
+        let mut file = b"\x80\x02\x8a\x1a\x01".to_vec();
+        file.extend([0; 24]);
+        file.extend(b"\xff.");
+        assert_eq!(
+            parse(&file).unwrap().to_string(),
+            "-1606938044258990275541962092341162602522202993782792835301375"
+        );
+
+        // 2**200 as LONG4.
+        let mut file = b"\x80\x02\x8b\x1b\x00\x00\x00".to_vec();
+        file.extend([0; 25]);
+        file.extend(b"\x01\x00.");
+        assert_eq!(
+            parse(&file).unwrap().to_string(),
+            "1606938044258990275541962092341162602522202993782792835301376"
+        );
+        assert!(matches!(
+            parse_err(b"\x80\x02\x8b\xff\xff\xff\xff."),
+            crate::PickleError::Malformed(_)
+        ));
+
+        // Text longs past 128 bits.
+        assert_eq!(
+            parse(b"L-1606938044258990275541962092341162602522202993782792835301375L\n.")
+                .unwrap()
+                .to_string(),
+            "-1606938044258990275541962092341162602522202993782792835301375"
+        );
+    }
+
+    #[test]
+    fn bytes_and_bytearray_opcodes() {
+        use crate::value::Value;
+
+        // pickle.dumps(bytearray(b'ab'), 5)
+        let file = b"\x80\x05\x95\r\x00\x00\x00\x00\x00\x00\x00\x96\x02\x00\x00\x00\x00\x00\x00\x00ab\x94.";
+        assert_eq!(parse(file).unwrap(), Value::ByteArray(b"ab".to_vec()));
+        assert_ne!(parse(file).unwrap(), Value::Bytes(b"ab".to_vec()));
+        assert_eq!(parse(b"\x80\x03C\x02abq\x00.").unwrap(), Value::Bytes(b"ab".to_vec()));
+        assert_eq!(
+            parse(b"\x80\x04\x8e\x02\x00\x00\x00\x00\x00\x00\x00ab.").unwrap(),
+            Value::Bytes(b"ab".to_vec())
+        );
+        assert_eq!(
+            parse(b"\x80\x04\x8d\x02\x00\x00\x00\x00\x00\x00\x00ab.").unwrap(),
+            Value::String("ab".to_string())
+        );
+    }
+
+    #[test]
+    fn length_limit_is_checked_before_allocating() {
+        use crate::{Limits, Parser, PickleError};
+
+        // BINBYTES8 claiming 1 TiB.
+        let file = b"\x80\x04\x8e\x00\x00\x00\x00\x00\x01\x00\x00.";
+        let mut reader = &file[..];
+        // Loaded later on, it has to outlive the parser.
+        let mut next_reader = &b"\x80\x03C\x05hello."[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_max_length(4);
+        let err = parser.parse().unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            PickleError::LimitExceeded { limit: "length", value: 0x100_0000_0000, max: 4 }
+        ));
+
+        // The limit is kept when loading another stream.
+        parser.load(&mut next_reader);
+        assert!(matches!(
+            parser.parse().unwrap_err().kind(),
+            PickleError::LimitExceeded { value: 5, max: 4, .. }
+        ));
+
+        // It is one of the limits, which `set_limits` sets all at once.
+        assert_eq!(parser.limits(), Limits { max_length: 4, ..Limits::default() });
+        parser.set_limits(Limits { max_ops: 10, ..Limits::default() });
+        assert_eq!(parser.limits().max_length, Limits::default().max_length);
+        parser.set_max_length(4);
+        assert_eq!(parser.limits(), Limits { max_length: 4, max_ops: 10, ..Limits::default() });
+    }
+
+    #[test]
+    fn ext_opcodes_use_the_copyreg_registry() {
+        use crate::{Parser, PickleError};
+
+        // copyreg.add_extension('collections', 'OrderedDict', 0x10), and
+        // likewise Counter as 0x1234 and deque as 0x123456.
+        fn parse_with_registry(mut bytes: &[u8]) -> Result<crate::value::Value<'static>, PickleError> {
+            let mut parser = Parser::from(&mut bytes);
+            parser.add_extension_code(0x10, "collections", "OrderedDict");
+            parser.add_extension_code(0x1234, "collections", "Counter");
+            parser.add_extension_code(0x123456, "collections", "deque");
+            parser.parse()
+        }
+
+        for (file, name) in [
+            (&b"\x80\x02\x82\x10."[..], "OrderedDict"),
+            (b"\x80\x02\x834\x12.", "Counter"),
+            // deque.__new__(deque) via NEWOBJ.
+            (b"\x80\x02\x84V4\x12\x00)\x81.", "deque"),
+        ] {
+            let inst = parse_with_registry(file).unwrap().as_instance().unwrap();
+            assert_eq!((inst.module(), inst.name()), ("collections".to_string(), name.to_string()));
+        }
+
+        assert!(matches!(
+            parse_with_registry(b"\x80\x02\x82\x11.").unwrap_err().kind(),
+            PickleError::UnregisteredExtension(0x11)
+        ));
+        assert!(matches!(
+            parse_with_registry(b"\x80\x02\x82\x00.").unwrap_err().kind(),
+            PickleError::Malformed(_)
+        ));
+        assert!(matches!(
+            parse_with_registry(b"\x80\x02\x84\xff\xff\xff\xff.").unwrap_err().kind(),
+            PickleError::Malformed(_)
+        ));
+        // Nothing is registered by default.
+        assert!(matches!(
+            parse(b"\x80\x02\x82\x10.").unwrap_err().kind(),
+            PickleError::UnregisteredExtension(0x10)
+        ));
+    }
+
+    #[test]
+    fn persistent_ids() {
+        use crate::value::Value;
+        use crate::{Parser, PickleError};
+
+        // ['ext:a', 1, 'ext:b'] with persistent_id returning ('storage', 'a') and
+        // ('storage', 'b') for the strings.
+        let file = b"\x80\x02]q\x00(X\x07\x00\x00\x00storageq\x01X\x01\x00\x00\x00aq\x02\x86q\x03QK\x01h\x01X\x01\x00\x00\x00bq\x04\x86q\x05Qe.";
+        let pid = |key: &str| {
+            Value::PersistentId(Box::new(Value::Tuple(vec![
+                Value::String("storage".to_string()),
+                Value::String(key.to_string()),
+            ])))
+        };
+        assert_eq!(
+            parse(file).unwrap(),
+            Value::List(vec![pid("a"), Value::UInt(1), pid("b")])
+        );
+
+        let mut loaded = vec![];
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_persistent_load(|pid| {
+            let key = pid.as_tuple().and_then(|mut t| t.pop()?.as_string()).unwrap();
+            loaded.push(key.clone());
+            Ok(Value::Bytes(key.into_bytes()))
+        });
+        assert_eq!(
+            parser.parse().unwrap(),
+            Value::List(vec![Value::Bytes(b"a".to_vec()), Value::UInt(1), Value::Bytes(b"b".to_vec())])
+        );
+        drop(parser);
+        assert_eq!(loaded, ["a", "b"]);
+
+        // Protocol 0 PERSID with a string pid.
+        assert_eq!(
+            parse(b"(lp0\nPa\naI1\naPb\na.").unwrap(),
+            Value::List(vec![
+                Value::PersistentId(Box::new(Value::String("a".to_string()))),
+                Value::Int(1),
+                Value::PersistentId(Box::new(Value::String("b".to_string()))),
+            ])
+        );
+
+        // Errors from the hook stop parsing.
+        let mut reader = &b"Pa\n."[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_persistent_load(|_| Err(PickleError::Malformed("unknown storage".to_string())));
+        let err = parser.parse().unwrap_err();
+        assert!(matches!(err.kind(), PickleError::Malformed(_)));
+        assert_eq!(err.offset(), Some(0));
+
+        assert!(matches!(parse_err(b"\x80\x02Q."), PickleError::StackUnderflow));
+    }
+
+    #[test]
+    fn out_of_band_buffers() {
+        use std::borrow::Cow;
+
+        use crate::value::Value;
+        use crate::{Parser, PickleError};
+
+        // pickle.dumps([PickleBuffer(bytearray(b'abc')), PickleBuffer(b'xy')], 5,
+        //              buffer_callback=buffers.append)
+        let file = b"\x80\x05\x95\x08\x00\x00\x00\x00\x00\x00\x00]\x94(\x97\x97\x98e.";
+        let expected = Value::List(vec![
+            Value::PickleBuffer { data: b"abc"[..].into(), readonly: false },
+            Value::PickleBuffer { data: b"xy"[..].into(), readonly: true },
+        ]);
+
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_buffers([&b"abc"[..], b"xy"]);
+        assert_eq!(parser.parse().unwrap(), expected);
+
+        // Borrowed buffers aren't copied.
+        let buffers = [b"abc".to_vec(), b"xy".to_vec()];
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_buffers(buffers.iter().map(Vec::as_slice));
+        let Value::List(items) = parser.parse().unwrap() else {
+            panic!("not a list");
+        };
+        for (item, buffer) in items.iter().zip(&buffers) {
+            let Value::PickleBuffer { data: Cow::Borrowed(data), .. } = item else {
+                panic!("not a borrowed buffer: {item:?}");
+            };
+            assert_eq!(data.as_ptr(), buffer.as_ptr());
+        }
+
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_buffers(vec![b"abc".to_vec(), b"xy".to_vec()]);
+        assert_eq!(parser.parse().unwrap(), expected);
+
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_buffers([b"abc".to_vec()]);
+        assert!(matches!(parser.parse().unwrap_err().kind(), PickleError::MissingBuffer));
+        assert!(matches!(parse_err(file), PickleError::MissingBuffer));
+
+        // In-band buffers are plain bytes and bytearrays, READONLY_BUFFER
+        // leaves bytes alone.
+        assert_eq!(
+            parse(b"\x80\x05C\x02xy\x98.").unwrap(),
+            Value::Bytes(b"xy".to_vec())
+        );
+        assert_eq!(
+            parse(b"\x80\x05\x96\x02\x00\x00\x00\x00\x00\x00\x00xy\x98.").unwrap(),
+            Value::PickleBuffer { data: b"xy"[..].into(), readonly: true }
+        );
+        assert!(matches!(
+            parse_err(b"\x80\x05K\x01\x98."),
+            PickleError::TypeMismatch { expected: "buffer", found: "int" }
+        ));
+    }
+
+    #[test]
+    fn extensions_capture_state_and_see_context() {
+        use crate::value::Value;
+        use crate::{Parser, PickleError};
+
+        // [P(1), P(2)] with P reduced to (P, (n,)).
+        let file = b"\x80\x02]q\x00(c__main__\nP\nq\x01K\x01\x85q\x02Rq\x03h\x01K\x02\x85q\x04Rq\x05e.";
+        let mut calls = vec![];
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.add_extension("__main__", "P", |ctx, args| {
+            assert_eq!(ctx.instance().as_key(), "__main__.P");
+            assert_eq!(ctx.version(), 2);
+            assert!(ctx.kwargs().is_none());
+            calls.push(args.to_string());
+            ctx.warn(format!("call {}", calls.len()));
+            Ok(Value::Int(calls.len() as i32))
+        });
+        assert_eq!(parser.parse().unwrap(), Value::List(vec![Value::Int(1), Value::Int(2)]));
+        assert_eq!(parser.warnings(), ["call 1", "call 2"]);
+        drop(parser);
+        assert_eq!(calls, ["(1)", "(2)"]);
+
+        // NEWOBJ_EX keyword arguments are on the context too.
+        let file = b"\x80\x04c__main__\nP\n)}\x8c\x01b\x8c\x01xs\x92.";
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.add_extension("__main__", "P", |ctx, _| {
+            Ok(ctx.kwargs().unwrap()["b"].clone())
+        });
+        assert_eq!(parser.parse().unwrap(), Value::String("x".to_string()));
+
+        // Errors stop parsing at the calling opcode.
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.add_extension("__main__", "P", |_, _| {
+            Err(PickleError::Malformed("no P here".to_string()))
+        });
+        let err = parser.parse().unwrap_err();
+        assert!(matches!(err.kind(), PickleError::Malformed(_)));
+        assert_eq!(err.op(), Some(&crate::op::Op::NewObjEx));
+    }
+
+    #[test]
+    fn safety_policy() {
+        use crate::policy::SafetyPolicy;
+        use crate::{Parser, PickleError};
+
+        // __reduce__ returning (os.system, ('echo hi',)), protocols 0, 2 and 4.
+        for (file, offset) in [
+            (&b"cposix\nsystem\np0\n(Vecho hi\np1\ntp2\nRp3\n."[..], 0),
+            (b"\x80\x02cposix\nsystem\nq\x00X\x07\x00\x00\x00echo hiq\x01\x85q\x02Rq\x03.", 2),
+            (b"\x80\x04\x95\"\x00\x00\x00\x00\x00\x00\x00\x8c\x05posix\x94\x8c\x06system\x94\x93\x94\x8c\x07echo hi\x94\x85\x94R\x94.", 28),
+        ] {
+            let err = parse(file).unwrap_err();
+            match err.kind() {
+                PickleError::ForbiddenGlobal { module, name } => {
+                    assert_eq!((module.as_str(), name.as_str()), ("posix", "system"))
+                }
+                e => panic!("unexpected error {e}"),
+            }
+            assert_eq!(err.offset(), Some(offset));
+        }
+
+        // STACK_GLOBAL("glob", "os.system") reaches os.system through
+        // the glob module's attributes.
+        let err = parse_err(b"\x80\x04\x8c\x04glob\x8c\tos.system\x93\x8c\x02id\x85R.");
+        assert!(matches!(err, PickleError::ForbiddenGlobal { .. }));
+
+        let file = b"\x80\x02cposix\nsystem\nq\x00X\x07\x00\x00\x00echo hiq\x01\x85q\x02Rq\x03.";
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_policy(SafetyPolicy::permissive());
+        assert_eq!(parser.parse().unwrap().to_string(), "*posix.system(('echo hi'))");
+
+        // pickle.dumps(OrderedDict, 2), only allowed when listed.
+        let file = b"\x80\x02ccollections\nOrderedDict\nq\x00.";
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_policy(SafetyPolicy::restricted());
+        assert!(matches!(parser.parse().unwrap_err().kind(), PickleError::ForbiddenGlobal { .. }));
+        let mut reader = &file[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.set_policy(SafetyPolicy::restricted().allow("collections.OrderedDict"));
+        assert!(parser.parse().is_ok());
+
+        // EXT and INST go through the same check.
+        let mut reader = &b"\x80\x02\x82\x01."[..];
+        let mut parser = Parser::from(&mut reader);
+        parser.add_extension_code(1, "subprocess", "Popen");
+        assert!(matches!(parser.parse().unwrap_err().kind(), PickleError::ForbiddenGlobal { .. }));
+        assert!(matches!(
+            parse_err(b"(S'id'\nibuiltins\neval\n."),
+            PickleError::ForbiddenGlobal { .. }
+        ));
+    }
+
+    #[test]
+    fn resource_limits() {
+        use crate::{Limits, Parser, PickleError};
+
+        fn parse_with(mut bytes: &[u8], limits: Limits) -> Result<crate::value::Value<'static>, PickleError> {
+            let mut parser = Parser::from(&mut bytes);
+            parser.set_limits(limits);
+            parser.parse()
+        }
+        fn limit_of(e: PickleError) -> &'static str {
+            match e.kind() {
+                PickleError::LimitExceeded { limit, .. } => limit,
+                e => panic!("unexpected error {e}"),
+            }
+        }
+
+        // Declared sizes aren't allocated upfront: a huge FRAME or
+        // BINBYTES8 on a short stream is just truncated, and one past
+        // the bytes a stream may have is an error before reading it.
+        assert!(matches!(
+            parse_err(b"\x80\x04\x95\x00\x00\xff\xff\x00\x00\x00\x00K\x01."),
+            PickleError::UnexpectedEof
+        ));
+        assert!(matches!(
+            parse_err(b"\x80\x04\x8e\x00\x00\xff\xff\x00\x00\x00\x00ab."),
+            PickleError::UnexpectedEof
+        ));
+        assert_eq!(limit_of(parse_err(b"\x80\x04\x95\x00\x00\x00\x00\x00\x01\x00\x00K\x01.")), "total bytes");
+        assert_eq!(limit_of(parse_err(b"\x80\x04\x8e\x00\x00\x00\x00\x00\x01\x00\x00ab.")), "total bytes");
+        assert_eq!(limit_of(parse_err(b"\x80\x04\x95\xff\xff\xff\xff\xff\xff\xff\xffK\x01.")), "frame size");
+
+        let framed = b"\x80\x04\x95\x03\x00\x00\x00\x00\x00\x00\x00K\x01.";
+        let limits = Limits { max_frame_size: 2, ..Limits::default() };
+        assert_eq!(limit_of(parse_with(framed, limits).unwrap_err()), "frame size");
+        let limits = Limits { max_total_bytes: 8, ..Limits::default() };
+        assert_eq!(limit_of(parse_with(framed, limits).unwrap_err()), "total bytes");
+        let limits = Limits { max_total_bytes: framed.len() as u64, ..Limits::default() };
+        assert!(parse_with(framed, limits).is_ok());
+
+        let limits = Limits { max_stack_depth: 2, ..Limits::default() };
+        assert!(parse_with(b"\x80\x02K\x01K\x02\x86.", limits).is_ok());
+        assert_eq!(limit_of(parse_with(b"\x80\x02K\x01K\x02K\x03\x87.", limits).unwrap_err()), "stack depth");
+        assert_eq!(limit_of(parse_with(b"\x80\x02(((N.", limits).unwrap_err()), "stack depth");
+
+        let limits = Limits { max_memo_entries: 1, ..Limits::default() };
+        assert!(parse_with(b"\x80\x02K\x01q\x00q\x00.", limits).is_ok());
+        assert_eq!(limit_of(parse_with(b"\x80\x02K\x01q\x00q\x01.", limits).unwrap_err()), "memo entries");
+
+        // l_{i+1} = [l_i, l_i], each GET copying twice as much as the last.
+        let mut laughs = b"\x80\x02]q\x00".to_vec();
+        for i in 0..40 {
+            laughs.extend([b'(', b'h', i, b'h', i, b'l', b'q', i + 1]);
+        }
+        laughs.push(b'.');
+        let limits = Limits { max_copied_values: 1 << 16, ..Limits::default() };
+        let err = parse_with(&laughs, limits).unwrap_err();
+        assert_eq!(limit_of(err), "copied values");
+        // PUTs copy too: 1 + 1 + 1 + 3 values here.
+        let limits = Limits { max_copied_values: 6, ..Limits::default() };
+        assert!(parse_with(b"\x80\x02]q\x00(h\x00h\x00lq\x01.", limits).is_ok());
+        assert_eq!(limit_of(parse_with(b"\x80\x02]q\x00(h\x00h\x00lq\x01(h\x01h\x01l.", limits).unwrap_err()), "copied values");
+        // A tuple of 3, memoized again and again.
+        let limits = Limits { max_copied_values: 10, ..Limits::default() };
+        assert!(parse_with(b"\x80\x02(NNNtq\x00q\x01.", limits).is_ok());
+        assert_eq!(limit_of(parse_with(b"\x80\x02(NNNtq\x00q\x01q\x02.", limits).unwrap_err()), "copied values");
+
+        let limits = Limits { max_ops: 3, ..Limits::default() };
+        assert!(parse_with(b"\x80\x02K\x01\x85.", limits).is_ok());
+        assert_eq!(limit_of(parse_with(b"\x80\x02K\x01\x85\x85.", limits).unwrap_err()), "opcode count");
+
+        // [[[...]]] nested with APPEND, at the default limit and one past it.
+        let nested = |depth: usize| {
+            let mut file = b"\x80\x02".to_vec();
+            file.extend(std::iter::repeat_n(b']', depth));
+            file.extend(std::iter::repeat_n(b'a', depth - 1));
+            file.push(b'.');
+            file
+        };
+        let value = parse(&nested(1000)).unwrap();
+        assert!(value.to_string().starts_with("[[[["));
+        let err = parse(&nested(1001)).unwrap_err();
+        assert_eq!(err.offset(), Some(2 + 1001 + 999));
+        assert_eq!(limit_of(err), "nesting depth");
+        let limits = Limits { max_nesting: 2, ..Limits::default() };
+        assert!(parse_with(b"\x80\x02)\x85.", limits).is_ok());
+        assert_eq!(limit_of(parse_with(b"\x80\x02)\x85\x85.", limits).unwrap_err()), "nesting depth");
+        // Objects are one level, whether their fields come from a state
+        // dict or their args from a tuple.
+        let object = b"\x80\x02c__main__\nC\n)\x81}X\x01\x00\x00\x00aK\x01sb";
+        assert!(parse_with(&[&object[..], b"\x85."].concat(), limits).is_ok());
+        assert_eq!(limit_of(parse_with(&[&object[..], b"\x85\x85."].concat(), limits).unwrap_err()), "nesting depth");
+        let object = b"\x80\x02c__main__\nC\nK\x01\x85\x81";
+        assert!(parse_with(&[&object[..], b"\x85."].concat(), limits).is_ok());
+        assert_eq!(limit_of(parse_with(&[&object[..], b"\x85\x85."].concat(), limits).unwrap_err()), "nesting depth");
+
+        // Depths are kept along with the values, so BUILD doesn't walk
+        // a large state again each time it puts its object back.
+        let mut file = b"\x80\x02c__main__\nC\n)\x81](".to_vec();
+        file.extend(std::iter::repeat_n(b'N', 400_000));
+        file.extend(b"eb");
+        file.extend(std::iter::repeat_n(*b"Nb", 4000).flatten());
+        file.push(b'.');
+        assert!(parse(&file).is_ok());
+    }
+
+    #[test]
+    fn frames() {
+        use crate::PickleError;
+
+        fn is_straddle(e: PickleError) -> bool {
+            matches!(e, PickleError::Malformed(reason) if reason.contains("straddles"))
+        }
+
+        // Protocol 4 writes large bytes objects outside of any frame,
+        // and reading resumes from the stream once a frame is used up.
+        let mixed = b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00]\x94C\x02ab\x95\x02\x00\x00\x00\x00\x00\x00\x00a.";
+        assert_eq!(parse(mixed).unwrap().to_string(), "[[61, 62]]");
+        let err = parse(&mixed[..mixed.len() - 1]).unwrap_err();
+        assert_eq!(err.offset(), Some(17));
+        assert!(matches!(err.kind(), PickleError::UnexpectedEof));
+
+        // Opcode in the frame, argument after it.
+        assert!(is_straddle(parse_err(b"\x80\x04\x95\x01\x00\x00\x00\x00\x00\x00\x00K\x01.")));
+        // Frame ends partway through an argument or a line.
+        assert!(is_straddle(parse_err(b"\x80\x04\x95\x03\x00\x00\x00\x00\x00\x00\x00C\x02ab.")));
+        assert!(is_straddle(parse_err(b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00I1\n.")));
+        // FRAME before the end of the current frame.
+        let err = parse(b"\x80\x04\x95\x0b\x00\x00\x00\x00\x00\x00\x00\x95\x01\x00\x00\x00\x00\x00\x00\x00NN.").unwrap_err();
+        assert_eq!(err.offset(), Some(11));
+        assert!(matches!(err.kind(), PickleError::Malformed(reason) if reason.contains("new frame")));
+    }
+
+    #[test]
+    fn object_graph() {
+        use crate::value::{Graph, Value};
+        use crate::{Limits, Parser, PickleError};
+
+        fn parse_graph(mut bytes: &[u8]) -> Graph<'static> {
+            Parser::from(&mut bytes).parse_graph().unwrap()
+        }
+
+        // x = [1, 2]; [x, x]. x is memoized before it is filled, so a
+        // plain tree only has a copy of the empty list.
+        let file = b"\x80\x02]q\x00(]q\x01(K\x01K\x02eh\x01e.";
+        assert_eq!(parse(file).unwrap().to_string(), "[[1, 2], []]");
+        let graph = parse_graph(file);
+        assert_eq!(graph.to_string(), "[[1, 2], [1, 2]]");
+        let Value::List(items) = graph.resolve(graph.root()) else {
+            panic!("expected a list");
+        };
+        assert!(matches!(items[..], [Value::Ref(a), Value::Ref(b)] if a == b));
+
+        // l = []; l.append(l) and d = {}; d['self'] = d
+        let graph = parse_graph(b"\x80\x02]q\x00h\x00a.");
+        assert_eq!(graph.to_string(), "[[...]]");
+        assert_eq!(format!("{:?}", graph.get(0).unwrap()), "List([Ref(0)])");
+        assert!(matches!(graph.materialize(), Err(PickleError::Cycle)));
+        let graph = parse_graph(b"\x80\x02}q\x00X\x04\x00\x00\x00selfq\x01h\x00s.");
+        assert_eq!(graph.to_string(), "{'self': {...}}");
+
+        // f = Foo(); f.me = f
+        let graph = parse_graph(b"\x80\x02c__main__\nFoo\nq\x00)\x81q\x01}q\x02X\x02\x00\x00\x00meq\x03h\x01sb.");
+        let Value::Object(foo) = graph.resolve(graph.root()) else {
+            panic!("expected an object");
+        };
+        assert_eq!(&foo.fields()["me"], graph.root());
+        assert!(matches!(graph.materialize(), Err(PickleError::Cycle)));
+
+        // An object whose __getstate__ returns [self], and a cycle in the
+        // stack snapshot of an error.
+        let graph = parse_graph(b"\x80\x02c__main__\nC\n)\x81q\x01]h\x01ab.");
+        assert!(graph.to_string().contains("state: [...]"));
+        let mut file = &b"\x80\x02]q\x00h\x00aK\x01K\x02a."[..];
+        let err = Parser::from(&mut file).parse_graph().unwrap_err();
+        assert!(err.to_string().contains("[[...]]"));
+        // The heap, and the memo entries referring to it, belong to the
+        // graph of the first pickle of a stream.
+        let mut file = &b"\x80\x02]q\x00K\x01a.\x80\x02h\x00K\x02a."[..];
+        let mut parser = Parser::from(&mut file);
+        assert_eq!(parser.parse_graph().unwrap().to_string(), "[1]");
+        let err = parser.parse_graph().unwrap_err();
+        assert!(matches!(err.kind(), PickleError::BadMemoIndex(0)));
+
+        // Without aliasing, the graph materializes to the plain tree.
+        let file = b"\x80\x04\x95\x1b\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x01a\x94]\x94(K\x01K\x02e\x8c\x01b\x94\x8f\x94(K\x03\x90u.";
+        let tree = parse(file).unwrap();
+        let graph = parse_graph(file);
+        assert_eq!(graph.materialize().unwrap(), tree);
+        // References compare by identity, like Python objects.
+        assert_ne!(graph.root(), &tree);
+        assert_eq!(graph, graph.clone());
+
+        // a = A(); a.d = {a: 1}. `a` is a key of the inner dict before
+        // BUILD sets its fields, and must still be found afterwards.
+        let graph = parse_graph(b"\x80\x02c__main__\nA\nq\x00)\x81q\x01}q\x02X\x01\x00\x00\x00dq\x03}q\x04h\x01K\x01ssb.");
+        let Value::Object(a) = graph.resolve(graph.root()) else {
+            panic!("expected an object");
+        };
+        let Value::Dict(d) = graph.resolve(&a.fields()["d"]) else {
+            panic!("expected a dict");
+        };
+        assert_eq!(d.get(graph.root()), Some(&Value::UInt(1)));
+
+        // Extensions can follow references through their context.
+        let mut file = &b"\x80\x02c__main__\nP\n]q\x00K\x01a\x85R."[..];
+        let mut parser = Parser::from(&mut file);
+        parser.add_extension("__main__", "P", |ctx, args| {
+            let id = args.as_tuple().unwrap()[0].clone().as_reference().unwrap();
+            Ok(ctx.get(id).unwrap().clone())
+        });
+        assert_eq!(parser.parse_graph().unwrap().root(), &Value::List(vec![Value::UInt(1)]));
+
+        // Lists L0..Ln, with L(i-1) appended to Li from Ln down, after Li
+        // was put in a tuple. Each list is one deep when it is checked,
+        // but following the references nests them n + 1 deep.
+        let n: u32 = 2000;
+        let mut chain = b"\x80\x02".to_vec();
+        for i in 0..=n {
+            chain.push(b']');
+            chain.push(b'r');
+            chain.extend(i.to_le_bytes());
+            chain.push(b'0');
+        }
+        for i in (1..=n).rev() {
+            chain.push(b'j');
+            chain.extend(i.to_le_bytes());
+            chain.push(b'j');
+            chain.extend((i - 1).to_le_bytes());
+            chain.extend(b"a\x850");
+        }
+        chain.push(b'j');
+        chain.extend(n.to_le_bytes());
+        chain.push(b'.');
+        let graph = parse_graph(&chain);
+        let shown = format!("{}[...]{}", "[".repeat(1000), "]".repeat(1000));
+        assert_eq!(graph.to_string(), shown);
+        let err = graph.materialize().unwrap_err();
+        assert!(matches!(err, PickleError::LimitExceeded { limit: "nesting depth", max: 1000, .. }));
+
+        // l0 = []; li = [l(i-1), l(i-1)]. The graph holds 41 lists, and
+        // its tree 2^41 - 1, which materialize counts as copies.
+        let mut doubling = b"\x80\x02]q\x00".to_vec();
+        for i in 1..=40u8 {
+            doubling.extend([b']', b'q', i, b'(', b'h', i - 1, b'h', i - 1, b'e']);
+        }
+        doubling.push(b'.');
+        let mut file = &doubling[..];
+        let mut parser = Parser::from(&mut file);
+        parser.set_limits(Limits { max_copied_values: 100_000, ..Limits::default() });
+        let err = parser.parse_graph().unwrap().materialize().unwrap_err();
+        assert!(matches!(err, PickleError::LimitExceeded { limit: "copied values", max: 100_000, .. }));
+
+        // Values and graphs can be moved to other threads.
+        let graph = parse_graph(b"\x80\x02]q\x00h\x00a.");
+        let value = parse(b"\x80\x02]q\x00h\x00a.").unwrap();
+        let shown = std::thread::spawn(move || (graph.to_string(), value.to_string()));
+        assert_eq!(shown.join().unwrap(), ("[[...]]".to_string(), "[[]]".to_string()));
+    }
 }
@@ -1,5 +1,7 @@
 // https://github.com/python/cpython/blob/3.12/Lib/pickletools.py
 
+use crate::error::PickleError;
+
 pub const MARK: u8 = 40;
 pub const EMPTY_TUPLE: u8 = 41;
 pub const STOP: u8 = 46;
@@ -216,10 +218,12 @@ impl From<Op> for u8 {
     }
 }
 
-impl From<u8> for Op {
+impl TryFrom<u8> for Op {
+    type Error = PickleError;
+
     #[inline]
-    fn from(value: u8) -> Self {
-        match value {
+    fn try_from(value: u8) -> Result<Self, PickleError> {
+        let op = match value {
             INT => Op::Int,
             BININT => Op::BinInt,
             BININT1 => Op::BinInt1,
@@ -288,7 +292,8 @@ impl From<u8> for Op {
             BYTEARRAY8 => Op::ByteArray8,
             NEXT_BUFFER => Op::NextBuffer,
             READONLY_BUFFER => Op::ReadonlyBuffer,
-            0..=u8::MAX => panic!("Can't parse unknown opcode {}", value),
-        }
+            0..=u8::MAX => return Err(PickleError::UnknownOpcode(value)),
+        };
+        Ok(op)
     }
 }
@@ -0,0 +1,188 @@
+// Which globals a pickle may reference, like restricting
+// `Unpickler.find_class`. Checked for GLOBAL, STACK_GLOBAL, INST and EXT*.
+// https://docs.python.org/3/library/pickle.html#restricting-globals
+
+use crate::error::PickleError;
+
+// Globals that run code, touch the filesystem or the network, or
+// reach into the interpreter. Loosely follows picklescan's denylist.
+const DANGEROUS_GLOBALS: &[&str] = &[
+    "builtins.eval",
+    "builtins.exec",
+    "builtins.compile",
+    "builtins.open",
+    "builtins.__import__",
+    "builtins.getattr",
+    "builtins.setattr",
+    "builtins.delattr",
+    "builtins.globals",
+    "builtins.locals",
+    "builtins.vars",
+    "builtins.input",
+    "builtins.breakpoint",
+    "__builtin__.eval",
+    "__builtin__.exec",
+    "__builtin__.execfile",
+    "__builtin__.compile",
+    "__builtin__.open",
+    "__builtin__.file",
+    "__builtin__.__import__",
+    "__builtin__.getattr",
+    "__builtin__.setattr",
+    "__builtin__.delattr",
+    "__builtin__.globals",
+    "__builtin__.locals",
+    "__builtin__.vars",
+    "__builtin__.input",
+    "__builtin__.raw_input",
+    "__builtin__.apply",
+    "os.*",
+    "posix.*",
+    "nt.*",
+    "subprocess.*",
+    "commands.*",
+    "popen2.*",
+    "sys.*",
+    "shutil.*",
+    "socket.*",
+    "pty.*",
+    "runpy.*",
+    "importlib.*",
+    "imp.*",
+    "pickle.*",
+    "_pickle.*",
+    "cPickle.*",
+    "marshal.*",
+    "ctypes.*",
+    "webbrowser.*",
+    "code.*",
+    "codeop.*",
+    "pdb.*",
+    "bdb.*",
+    "timeit.*",
+    "asyncio.*",
+    "multiprocessing.*",
+    "signal.*",
+    "platform.popen",
+    "operator.attrgetter",
+    "operator.methodcaller",
+];
+
+// Patterns are either an exact `module.name`, or `prefix.*` which
+// matches everything in that module and its submodules. Deny patterns
+// win over allow patterns, anything else gets the default verdict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyPolicy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    deny_by_default: bool,
+}
+
+impl SafetyPolicy {
+    // Accept every global.
+    pub fn permissive() -> Self {
+        SafetyPolicy {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            deny_by_default: false,
+        }
+    }
+
+    // Reject every global that wasn't explicitly allowed.
+    pub fn restricted() -> Self {
+        Self::permissive().deny_by_default(true)
+    }
+
+    // Accept everything but the built-in list of dangerous globals.
+    pub fn preset() -> Self {
+        DANGEROUS_GLOBALS
+            .iter()
+            .fold(Self::permissive(), |policy, pattern| policy.deny(pattern))
+    }
+
+    pub fn allow(mut self, pattern: &str) -> Self {
+        self.allow.push(pattern.to_string());
+        self
+    }
+
+    pub fn deny(mut self, pattern: &str) -> Self {
+        self.deny.push(pattern.to_string());
+        self
+    }
+
+    pub fn deny_by_default(mut self, deny_by_default: bool) -> Self {
+        self.deny_by_default = deny_by_default;
+        self
+    }
+
+    // From protocol 4 the name is an attribute path, so `glob.os.system`
+    // reaches `os.system` through `glob`. Deny patterns are checked against
+    // each such path, allow patterns only against the global as written.
+    pub fn is_allowed(&self, module: &str, name: &str) -> bool {
+        let key = format!("{module}.{name}");
+        let attributes = std::iter::successors(Some(name), |n| n.split_once('.').map(|(_, rest)| rest));
+        let mut paths = std::iter::once(key.as_str()).chain(attributes);
+        if paths.any(|path| self.deny.iter().any(|p| matches(p, path))) {
+            return false;
+        }
+        !self.deny_by_default || self.allow.iter().any(|p| matches(p, &key))
+    }
+
+    pub fn check(&self, module: &str, name: &str) -> Result<(), PickleError> {
+        if self.is_allowed(module, name) {
+            Ok(())
+        } else {
+            Err(PickleError::ForbiddenGlobal {
+                module: module.to_string(),
+                name: name.to_string(),
+            })
+        }
+    }
+}
+
+impl Default for SafetyPolicy {
+    fn default() -> Self {
+        Self::preset()
+    }
+}
+
+fn matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) if prefix.ends_with('.') => key.starts_with(prefix),
+        _ => pattern == key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SafetyPolicy;
+
+    #[test]
+    fn patterns() {
+        let policy = SafetyPolicy::preset();
+        assert!(!policy.is_allowed("posix", "system"));
+        assert!(!policy.is_allowed("os.path", "join"));
+        assert!(!policy.is_allowed("builtins", "eval"));
+        assert!(!policy.is_allowed("subprocess", "Popen"));
+        assert!(policy.is_allowed("builtins", "set"));
+        assert!(policy.is_allowed("collections", "OrderedDict"));
+        // `os.*` is not a plain string prefix.
+        assert!(policy.is_allowed("osmosis", "run"));
+        // Dotted names reach other modules through attributes.
+        assert!(!policy.is_allowed("glob", "os.system"));
+        assert!(!policy.is_allowed("collections", "abc.sys.modules"));
+        assert!(!policy.is_allowed("typing", "builtins.eval"));
+        assert!(policy.is_allowed("collections", "OrderedDict.fromkeys"));
+
+        let policy = SafetyPolicy::restricted()
+            .allow("collections.OrderedDict")
+            .allow("torch._utils.*")
+            .deny("torch._utils._rebuild_parameter");
+        assert!(policy.is_allowed("collections", "OrderedDict"));
+        assert!(!policy.is_allowed("collections", "Counter"));
+        assert!(policy.is_allowed("torch._utils", "_rebuild_tensor_v2"));
+        assert!(!policy.is_allowed("torch._utils", "_rebuild_parameter"));
+
+        assert!(SafetyPolicy::permissive().is_allowed("posix", "system"));
+    }
+}
@@ -0,0 +1,607 @@
+// Static scanner for untrusted pickles, in the spirit of picklescan
+// and fickling. Opcodes are decoded but never executed, so nothing is
+// built and no extension runs: this only reports what a full parse (or
+// Python's `pickle.load`) would import and call.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::PickleError;
+use crate::op::Op;
+use crate::policy::SafetyPolicy;
+use crate::vm::VM;
+use crate::zip;
+
+// Globals commonly found in data-only pickles, numpy arrays and
+// torch checkpoints.
+const SAFE_GLOBALS: &[&str] = &[
+    "builtins.set",
+    "builtins.frozenset",
+    "builtins.list",
+    "builtins.dict",
+    "builtins.tuple",
+    "builtins.bytes",
+    "builtins.bytearray",
+    "builtins.complex",
+    "builtins.slice",
+    "builtins.range",
+    "builtins.object",
+    "__builtin__.set",
+    "__builtin__.frozenset",
+    "__builtin__.object",
+    "copy_reg._reconstructor",
+    "copyreg._reconstructor",
+    "_codecs.encode",
+    "collections.OrderedDict",
+    "collections.defaultdict",
+    "collections.Counter",
+    "collections.deque",
+    "datetime.date",
+    "datetime.datetime",
+    "datetime.time",
+    "datetime.timedelta",
+    "datetime.timezone",
+    "decimal.Decimal",
+    "uuid.UUID",
+    "numpy.dtype",
+    "numpy.ndarray",
+    "numpy.core.multiarray._reconstruct",
+    "numpy.core.multiarray.scalar",
+    "numpy._core.multiarray._reconstruct",
+    "numpy._core.multiarray.scalar",
+    "torch._utils.*",
+    "torch.storage._load_from_bytes",
+    "torch.BoolStorage",
+    "torch.ByteStorage",
+    "torch.CharStorage",
+    "torch.ShortStorage",
+    "torch.IntStorage",
+    "torch.LongStorage",
+    "torch.HalfStorage",
+    "torch.FloatStorage",
+    "torch.DoubleStorage",
+    "torch.BFloat16Storage",
+    "torch.Size",
+    "torch.device",
+    "torch.dtype",
+    "torch.float16",
+    "torch.float32",
+    "torch.float64",
+    "torch.bfloat16",
+    "torch.int64",
+    "torch.int32",
+    "torch.bool",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Risk {
+    // Known to only build data.
+    Safe,
+    // Neither known to be safe nor to be dangerous.
+    Unknown,
+    // Runs code, touches the system, or could not be resolved statically.
+    Dangerous,
+}
+
+// A global imported by GLOBAL, STACK_GLOBAL, INST or EXT*.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Global {
+    pub module: String,
+    pub name: String,
+    // Absolute offset of the importing opcode.
+    pub offset: u64,
+    pub risk: Risk,
+}
+
+// An opcode that calls whatever is on the stack: REDUCE, INST, OBJ,
+// NEWOBJ or NEWOBJ_EX.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallSite {
+    pub op: Op,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanReport {
+    pub globals: Vec<Global>,
+    pub calls: Vec<CallSite>,
+    // Offset of the bytes after the last complete pickle, if they don't
+    // decode as another one. Globals they import up to the point they
+    // stop decoding are still reported, since a loader would get there.
+    pub trailing: Option<u64>,
+}
+
+impl ScanReport {
+    // Highest risk among the imported globals.
+    pub fn risk(&self) -> Risk {
+        self.globals
+            .iter()
+            .map(|g| g.risk)
+            .max()
+            .unwrap_or(Risk::Safe)
+    }
+
+    pub fn dangerous_globals(&self) -> impl Iterator<Item = &Global> {
+        self.globals.iter().filter(|g| g.risk == Risk::Dangerous)
+    }
+}
+
+// Scan a pickle stream. Pickles written back to back, like the legacy
+// torch format does, are scanned until the end of the stream or until
+// the bytes left stop decoding as a pickle of any protocol.
+pub fn scan(reader: &mut dyn Read) -> Result<ScanReport, PickleError> {
+    let mut scanner = Scanner::new();
+    scanner.scan_pickle(reader)?;
+    loop {
+        let mut byte = [0];
+        match reader.read_exact(&mut byte) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            r => r?,
+        }
+        let start = scanner.base;
+        match scanner.scan_pickle(&mut (&byte[..]).chain(&mut *reader)) {
+            Err(e) if !matches!(e.kind(), PickleError::Io(_)) => {
+                scanner.report.trailing = Some(start);
+                break;
+            }
+            r => r?,
+        }
+    }
+    Ok(scanner.report)
+}
+
+// Scan every pickle of a torch zip archive (`archive/data.pkl`, and
+// `constants.pkl` for TorchScript), keyed by entry name.
+pub fn scan_archive<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<Vec<(String, ScanReport)>, PickleError> {
+    let mut reports = vec![];
+    for entry in zip::entries(reader)? {
+        if !entry.name.ends_with(".pkl") {
+            continue;
+        }
+        let size = zip::open_entry(reader, &entry)?;
+        let report = scan(&mut reader.by_ref().take(size))?;
+        reports.push((entry.name, report));
+    }
+    Ok(reports)
+}
+
+// Scan a model file, which is either a zip archive or a raw pickle.
+// Raw pickles are reported under an empty name.
+pub fn scan_file<R: Read + Seek>(reader: &mut R) -> Result<Vec<(String, ScanReport)>, PickleError> {
+    let mut magic = [0; 4];
+    let is_zip = match reader.read_exact(&mut magic) {
+        Ok(()) => magic == zip::LOCAL_HEADER_SIGNATURE,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    if is_zip {
+        scan_archive(reader)
+    } else {
+        Ok(vec![(String::new(), scan(reader)?)])
+    }
+}
+
+struct Scanner {
+    report: ScanReport,
+    dangerous: SafetyPolicy,
+    safe: SafetyPolicy,
+    // Offset of the current pickle in the stream.
+    base: u64,
+}
+
+impl Scanner {
+    fn new() -> Self {
+        Scanner {
+            report: ScanReport::default(),
+            dangerous: SafetyPolicy::preset(),
+            safe: SAFE_GLOBALS
+                .iter()
+                .fold(SafetyPolicy::restricted(), |policy, pattern| {
+                    policy.allow(pattern)
+                }),
+            base: 0,
+        }
+    }
+
+    fn classify(&self, module: &str, name: &str) -> Risk {
+        if !self.dangerous.is_allowed(module, name) {
+            Risk::Dangerous
+        } else if self.safe.is_allowed(module, name) {
+            Risk::Safe
+        } else {
+            Risk::Unknown
+        }
+    }
+
+    fn add_global(&mut self, module: String, name: String, risk: Risk, offset: u64) {
+        self.report.globals.push(Global {
+            module,
+            name,
+            offset,
+            risk,
+        });
+    }
+
+    // Walk one pickle up to its STOP. STACK_GLOBAL takes its module and
+    // name from the stack, so the stack is followed through every opcode,
+    // and strings through the memo, to recover them.
+    fn scan_pickle(&mut self, reader: &mut dyn Read) -> Result<(), PickleError> {
+        let mut vm = VM::from(reader);
+        vm.set_skip_payloads(true);
+        let mut stack = ShadowStack::default();
+        let mut memo: HashMap<usize, Option<String>> = HashMap::new();
+        loop {
+            let (offset, op, arg) = vm
+                .next_instruction()
+                .map_err(|e| shift_offset(e, self.base))?;
+            let offset = self.base + offset;
+            match (op, arg) {
+                (op @ (Op::GlobalOpcode | Op::Inst), arg) => {
+                    match arg.as_string() {
+                        Some(s) => {
+                            let (module, name) = s.split_once('\n').unwrap_or((&s, ""));
+                            let risk = self.classify(module, name);
+                            self.add_global(module.to_string(), name.to_string(), risk, offset);
+                        }
+                        None => self.add_unresolved(offset),
+                    }
+                    if op == Op::Inst {
+                        self.report.calls.push(CallSite { op, offset });
+                        stack.pop_mark();
+                    }
+                    stack.push(None);
+                }
+                (Op::StackGlobal, _) => {
+                    let name = stack.pop();
+                    let module = stack.pop();
+                    match (module, name) {
+                        (Some(module), Some(name)) => {
+                            let risk = self.classify(&module, &name);
+                            self.add_global(module, name, risk, offset);
+                        }
+                        // Built some other way, which only a malicious
+                        // pickle would bother doing.
+                        _ => self.add_unresolved(offset),
+                    }
+                    stack.push(None);
+                }
+                // Codes are resolved through a registry only known at load time.
+                (Op::Ext1 | Op::Ext2 | Op::Ext4, arg) => {
+                    let code = arg.as_int().unwrap_or_default();
+                    self.add_global(
+                        "copyreg".to_string(),
+                        format!("<extension {code}>"),
+                        Risk::Unknown,
+                        offset,
+                    );
+                    stack.push(None);
+                }
+                (op @ (Op::Reduce | Op::NewObj), _) => {
+                    self.report.calls.push(CallSite { op, offset });
+                    stack.pop_n(2);
+                    stack.push(None);
+                }
+                (Op::NewObjEx, _) => {
+                    self.report.calls.push(CallSite { op: Op::NewObjEx, offset });
+                    stack.pop_n(3);
+                    stack.push(None);
+                }
+                (Op::Obj, _) => {
+                    self.report.calls.push(CallSite { op: Op::Obj, offset });
+                    stack.pop_mark();
+                    stack.push(None);
+                }
+                // Protocol 0 and 1 strings that aren't utf-8 are bytes.
+                (
+                    Op::ShortBinunicde
+                    | Op::BinUnicode
+                    | Op::BinUnicode8
+                    | Op::Unicode
+                    | Op::String
+                    | Op::BinString
+                    | Op::ShortBinstring,
+                    arg,
+                ) => stack.push(arg.as_string()),
+                (Op::Put | Op::BinPut | Op::LongBinPut, arg) => {
+                    let idx = arg.as_uint().unwrap_or_default() as usize;
+                    memo.insert(idx, stack.top());
+                }
+                (Op::Memoize, _) => {
+                    memo.insert(memo.len(), stack.top());
+                }
+                (Op::Get | Op::BinGet | Op::LongBinGet, arg) => {
+                    let idx = arg.as_uint().unwrap_or_default() as usize;
+                    stack.push(memo.get(&idx).cloned().flatten());
+                }
+                (Op::Dup, _) => stack.push(stack.top()),
+                (Op::Mark, _) => stack.mark(),
+                (Op::PopMark, _) => stack.pop_mark(),
+                (Op::Pop, _) => stack.pop_or_mark(),
+                (Op::Append, _) => stack.pop_n(1),
+                (Op::SetItem, _) => stack.pop_n(2),
+                (Op::Appends | Op::SetItems | Op::AddItems, _) => stack.pop_mark(),
+                (Op::List | Op::Tuple | Op::Dict | Op::FrozenSet, _) => {
+                    stack.pop_mark();
+                    stack.push(None);
+                }
+                (Op::Tuple1 | Op::BinPersid, _) => {
+                    stack.pop_n(1);
+                    stack.push(None);
+                }
+                (Op::Tuple2 | Op::Build, _) => {
+                    stack.pop_n(2);
+                    stack.push(None);
+                }
+                (Op::Tuple3, _) => {
+                    stack.pop_n(3);
+                    stack.push(None);
+                }
+                (
+                    Op::Int
+                    | Op::BinInt
+                    | Op::BinInt1
+                    | Op::BinInt2
+                    | Op::Long
+                    | Op::Long1
+                    | Op::Long4
+                    | Op::BinBytes
+                    | Op::ShortBinbytes
+                    | Op::BinBytes8
+                    | Op::ByteArray8
+                    | Op::None
+                    | Op::NewTrue
+                    | Op::NewFalse
+                    | Op::Float
+                    | Op::BinFloat
+                    | Op::EmptyList
+                    | Op::EmptyTuple
+                    | Op::EmptyDict
+                    | Op::EmptySet
+                    | Op::Persid
+                    | Op::NextBuffer,
+                    _,
+                ) => stack.push(None),
+                (Op::Frame | Op::Proto | Op::ReadonlyBuffer, _) => {}
+                (Op::Stop, _) => break,
+            }
+        }
+        self.base += vm.position();
+        Ok(())
+    }
+
+    // A global that can't be known without running the pickle.
+    fn add_unresolved(&mut self, offset: u64) {
+        self.add_global("?".to_string(), "?".to_string(), Risk::Dangerous, offset);
+    }
+}
+
+// What the VM's stack would hold, as far as the scanner cares: the
+// strings, with everything else unknown. Follows the stack effect of
+// every opcode so STACK_GLOBAL sees the two values on top of the stack.
+#[derive(Default)]
+struct ShadowStack {
+    items: Vec<Option<String>>,
+    marks: Vec<usize>,
+}
+
+impl ShadowStack {
+    fn push(&mut self, item: Option<String>) {
+        self.items.push(item);
+    }
+
+    // Popping past the topmost mark fails the real parse, so
+    // there is nothing to recover.
+    fn pop(&mut self) -> Option<String> {
+        if self.items.len() <= self.floor() {
+            return None;
+        }
+        self.items.pop().flatten()
+    }
+
+    fn pop_n(&mut self, n: usize) {
+        for _ in 0..n {
+            self.pop();
+        }
+    }
+
+    fn top(&self) -> Option<String> {
+        if self.items.len() <= self.floor() {
+            return None;
+        }
+        self.items.last().cloned().flatten()
+    }
+
+    fn mark(&mut self) {
+        self.marks.push(self.items.len());
+    }
+
+    fn pop_mark(&mut self) {
+        let mark = self.marks.pop().unwrap_or(0);
+        self.items.truncate(mark);
+    }
+
+    // Like the VM's POP, which pops the mark once nothing is above it.
+    fn pop_or_mark(&mut self) {
+        if self.items.len() > self.floor() {
+            self.items.pop();
+        } else {
+            self.pop_mark();
+        }
+    }
+
+    fn floor(&self) -> usize {
+        self.marks.last().copied().unwrap_or(0)
+    }
+}
+
+// Make the offset of an error absolute when scanning several pickles.
+fn shift_offset(e: PickleError, base: u64) -> PickleError {
+    match e {
+        PickleError::Context {
+            offset,
+            op,
+            stack,
+            source,
+        } => PickleError::Context {
+            offset: base + offset,
+            op,
+            stack,
+            source,
+        },
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_stack_global_through_the_memo() {
+        // pickle.dumps([os.system, os.getcwd], 4), the second global
+        // reuses the memoized module name.
+        let file = b"\x80\x04\x95%\x00\x00\x00\x00\x00\x00\x00]\x94(\x8c\x05posix\x94\x8c\x06system\x94\x93\x94h\x01\x8c\x06getcwd\x94\x93\x94e.";
+        let report = scan(&mut &file[..]).unwrap();
+        let globals: Vec<_> = report
+            .globals
+            .iter()
+            .map(|g| (g.module.as_str(), g.name.as_str(), g.offset, g.risk))
+            .collect();
+        assert_eq!(
+            globals,
+            [
+                ("posix", "system", 31, Risk::Dangerous),
+                ("posix", "getcwd", 44, Risk::Dangerous),
+            ]
+        );
+        assert!(report.calls.is_empty());
+        assert_eq!(report.risk(), Risk::Dangerous);
+    }
+
+    #[test]
+    fn stack_global_reads_the_top_of_the_stack() {
+        // Decoy strings pushed after the real module and name, then
+        // popped with POP or POP_MARK before STACK_GLOBAL.
+        let files: [&[u8]; 2] = [
+            b"\x80\x04\x8c\x05posix\x8c\x06system\x8c\x08builtins\x8c\x03set00\x93N\x85R.",
+            b"\x80\x04\x8c\x05posix\x8c\x06system(\x8c\x08builtins\x8c\x03set1\x93N\x85R.",
+        ];
+        for file in files {
+            let report = scan(&mut &file[..]).unwrap();
+            assert_eq!(report.globals.len(), 1);
+            assert_eq!(report.globals[0].module, "posix");
+            assert_eq!(report.globals[0].name, "system");
+            assert_eq!(report.risk(), Risk::Dangerous);
+        }
+
+        // Dotted names can lead anywhere.
+        let file = b"\x80\x04\x8c\x04glob\x8c\tos.system\x93.";
+        assert_eq!(scan(&mut &file[..]).unwrap().risk(), Risk::Dangerous);
+
+        // Strings consumed by a tuple are gone as well.
+        let report = scan(&mut &b"\x80\x04\x8c\x01a\x8c\x01b\x86N\x93."[..]).unwrap();
+        assert_eq!(report.globals[0].module, "?");
+    }
+
+    #[test]
+    fn reports_calls_and_risk() {
+        // pickle.dumps(collections.OrderedDict(), 2)
+        let file = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01.";
+        let report = scan(&mut &file[..]).unwrap();
+        assert_eq!(report.globals.len(), 1);
+        assert_eq!(report.risk(), Risk::Safe);
+        assert_eq!(report.calls, [CallSite { op: Op::Reduce, offset: 30 }]);
+
+        let report = scan(&mut &b"(S'x'\nimymod\nThing\n."[..]).unwrap();
+        assert_eq!(report.risk(), Risk::Unknown);
+        assert_eq!(report.calls, [CallSite { op: Op::Inst, offset: 6 }]);
+
+        // Module and name built by a call can't be resolved.
+        let report = scan(&mut &b"\x80\x04N\x85N\x85\x93."[..]).unwrap();
+        assert_eq!(report.globals[0].module, "?");
+        assert_eq!(report.risk(), Risk::Dangerous);
+    }
+
+    #[test]
+    fn scans_pickles_back_to_back() {
+        // A harmless pickle followed by os.system, like the legacy torch
+        // format's sequence of pickles.
+        let mut file = b"\x80\x02K\x01.".to_vec();
+        file.extend(b"\x80\x02cposix\nsystem\nq\x00.");
+        file.extend(b"raw storage bytes");
+        let report = scan(&mut &file[..]).unwrap();
+        assert_eq!(report.globals.len(), 1);
+        assert_eq!(report.globals[0].offset, 7);
+        assert_eq!(report.risk(), Risk::Dangerous);
+        assert_eq!(report.trailing, Some(24));
+
+        // Later pickles needn't start with PROTO: the legacy loader
+        // reads a protocol 0 pickle just as well.
+        let mut file = b"\x80\x02K\x01.".to_vec();
+        file.extend(b"cposix\nsystem\n(S'id'\ntR.");
+        let report = scan(&mut &file[..]).unwrap();
+        assert_eq!(report.globals[0].module, "posix");
+        assert_eq!(report.globals[0].offset, 5);
+        assert_eq!(report.calls, [CallSite { op: Op::Reduce, offset: 27 }]);
+        assert_eq!(report.trailing, None);
+    }
+
+    #[test]
+    fn skips_bytes_payloads() {
+        // BINBYTES8 and BYTEARRAY8 with 1 MiB payloads, then os.system.
+        let mut file = b"\x80\x05\x8e\x00\x00\x10\x00\x00\x00\x00\x00".to_vec();
+        file.extend(vec![0; 1 << 20]);
+        file.extend(b"\x96\x00\x00\x10\x00\x00\x00\x00\x00");
+        file.extend(vec![0; 1 << 20]);
+        file.extend(b"\x8c\x05posix\x8c\x06system\x93.");
+        let report = scan(&mut &file[..]).unwrap();
+        assert_eq!(report.globals[0].offset, (2 << 20) + 35);
+        assert_eq!(report.risk(), Risk::Dangerous);
+
+        // The payloads are never copied out of the stream.
+        let mut reader = &file[..];
+        let mut vm = VM::from(&mut reader);
+        vm.set_skip_payloads(true);
+        assert_eq!(vm.next_instruction().unwrap().2, crate::value::Value::Bytes(vec![]));
+        assert_eq!(vm.next_instruction().unwrap().2, crate::value::Value::ByteArray(vec![]));
+        assert_eq!(vm.position(), (2 << 20) + 20);
+
+        // Same inside a frame, and a truncated payload still fails.
+        let file = b"\x80\x05\x95\x0b\x00\x00\x00\x00\x00\x00\x00B\x04\x00\x00\x00spamN.";
+        assert_eq!(scan(&mut &file[..]).unwrap(), ScanReport::default());
+        let file = b"\x80\x05B\x04\x00\x00\x00spa";
+        assert!(scan(&mut &file[..]).is_err());
+    }
+
+    #[test]
+    fn scans_torch_zip_archives() {
+        // zipfile with archive/data.pkl = pickle.dumps(os.system, 2)
+        // and an archive/data/0 storage.
+        let file: Vec<u8> = [
+            &b"PK\x03\x04\x14\x00\x00\x00\x00\x00\x00\x00!XpA\xa6\xd7\x13\x00\x00\x00\x13"[..],
+            &b"\x00\x00\x00\x10\x00\x00\x00archive/data.pkl\x80\x02cposix\nsystem\nq\x00.PK"[..],
+            &b"\x03\x04\x14\x00\x00\x00\x00\x00\x00\x00!X\x1c\xdfD!\x04\x00\x00\x00\x04\x00"[..],
+            &b"\x00\x00\x0e\x00\x00\x00archive/data/0\x00\x00\x00\x00PK\x01\x02\x14\x03\x14"[..],
+            &b"\x00\x00\x00\x00\x00\x00\x00!XpA\xa6\xd7\x13\x00\x00\x00\x13\x00\x00\x00\x10"[..],
+            &b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x01\x00\x00\x00\x00archive/"[..],
+            &b"data.pklPK\x01\x02\x14\x03\x14\x00\x00\x00\x00\x00\x00\x00!X\x1c\xdfD!\x04"[..],
+            &b"\x00\x00\x00\x04\x00\x00\x00\x0e\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"[..],
+            &b"\x80\x01A\x00\x00\x00archive/data/0PK\x05\x06\x00\x00\x00\x00\x02\x00\x02"[..],
+            &b"\x00z\x00\x00\x00q\x00\x00\x00\x00\x00"[..],
+        ]
+        .concat();
+        let reports = scan_file(&mut std::io::Cursor::new(&file)).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].0, "archive/data.pkl");
+        assert_eq!(reports[0].1.risk(), Risk::Dangerous);
+
+        let reports = scan_file(&mut std::io::Cursor::new(b"\x80\x02K\x01.")).unwrap();
+        assert_eq!(reports[0].0, "");
+        assert_eq!(reports[0].1, ScanReport::default());
+
+        assert!(scan_archive(&mut std::io::Cursor::new(b"PK\x03\x04")).is_err());
+    }
+}
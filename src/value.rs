@@ -1,25 +1,76 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 
+use crate::bigint::BigInt;
+use crate::error::PickleError;
+use crate::vm::Limits;
+
+// Values borrow from the out-of-band buffers given to the parser, so
+// `'a` is theirs. Values of a stream without such buffers are `'static`.
 #[derive(Debug, Clone)]
-pub enum Value {
+pub enum Value<'a> {
     Bool(bool),
     String(String),
     Int(i32),
     UInt(u32),
     Long(i128),
     ULong(u128),
+    BigInt(BigInt),
     Float(f64),
-    Tuple(Vec<Value>),
-    List(Vec<Value>),
-    Dict(HashMap<Value, Value>),
+    Tuple(Vec<Value<'a>>),
+    List(Vec<Value<'a>>),
+    Dict(HashMap<Value<'a>, Value<'a>>),
+    Set(HashSet<Value<'a>>),
+    FrozenSet(HashSet<Value<'a>>),
     Bytes(Vec<u8>),
-    Object(Instance),
-    Callable(Instance, Box<Value>),
-    Mark,
+    ByteArray(Vec<u8>),
+    Object(Instance<'a>),
+    Callable(Instance<'a>, Box<Value<'a>>),
+    // Reference to an object stored outside the pickle, as pushed by
+    // PERSID and BINPERSID when no persistent_load hook is set.
+    PersistentId(Box<Value<'a>>),
+    // Out-of-band buffer pushed by NEXT_BUFFER, read-only once
+    // READONLY_BUFFER was applied. Borrowed buffers stay borrowed.
+    PickleBuffer { data: Cow<'a, [u8]>, readonly: bool },
+    // Memoized mutable value kept in the heap of a `Graph`, only found
+    // in values parsed by `Parser::parse_graph`. Compared and hashed by
+    // identity, like Python objects.
+    Ref(usize),
     None,
 }
 
-impl Value {
+impl<'a> Value<'a> {
+    // Python-ish name of the value's type, used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::String(_) => "str",
+            Value::Int(_)
+            | Value::UInt(_)
+            | Value::Long(_)
+            | Value::ULong(_)
+            | Value::BigInt(_) => "int",
+            Value::Float(_) => "float",
+            Value::Tuple(_) => "tuple",
+            Value::List(_) => "list",
+            Value::Dict(_) => "dict",
+            Value::Set(_) => "set",
+            Value::FrozenSet(_) => "frozenset",
+            Value::Bytes(_) => "bytes",
+            Value::ByteArray(_) => "bytearray",
+            Value::Object(_) => "object",
+            Value::Callable(_, _) => "callable",
+            Value::PersistentId(_) => "persistent_id",
+            Value::PickleBuffer { .. } => "PickleBuffer",
+            Value::Ref(_) => "reference",
+            Value::None => "None",
+        }
+    }
+
     pub fn as_bool(self) -> Option<bool> {
         if let Self::Bool(x) = self {
             Some(x)
@@ -68,6 +119,29 @@ impl Value {
         }
     }
 
+    pub fn as_bigint(self) -> Option<BigInt> {
+        if let Self::BigInt(x) = self {
+            Some(x)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_set(self) -> Option<HashSet<Value<'a>>> {
+        match self {
+            Self::Set(x) | Self::FrozenSet(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_tuple(self) -> Option<Vec<Value<'a>>> {
+        if let Self::Tuple(x) = self {
+            Some(x)
+        } else {
+            None
+        }
+    }
+
     pub fn as_bytes(self) -> Option<Vec<u8>> {
         if let Self::Bytes(x) = self {
             Some(x)
@@ -76,7 +150,31 @@ impl Value {
         }
     }
 
-    pub fn as_instance(self) -> Option<Instance> {
+    pub fn as_bytearray(self) -> Option<Vec<u8>> {
+        if let Self::ByteArray(x) = self {
+            Some(x)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_persistent_id(self) -> Option<Value<'a>> {
+        if let Self::PersistentId(x) = self {
+            Some(*x)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_pickle_buffer(self) -> Option<Cow<'a, [u8]>> {
+        if let Self::PickleBuffer { data, .. } = self {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_instance(self) -> Option<Instance<'a>> {
         if let Self::Object(x) = self {
             Some(x)
         } else {
@@ -84,23 +182,41 @@ impl Value {
         }
     }
 
+    pub fn as_reference(self) -> Option<usize> {
+        if let Self::Ref(x) = self {
+            Some(x)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct Instance {
+pub struct Instance<'a> {
     name: String,
     module: String,
-    fields: HashMap<String, Value>,
-    pub args: Vec<Value>,
-    pub kwargs: Option<HashMap<String, Value>>,
+    fields: HashMap<String, Value<'a>>,
+    // Entries of a dict state whose key isn't a str. Python keeps them
+    // in __dict__ as well, where only vars() can reach them.
+    other_fields: HashMap<Value<'a>, Value<'a>>,
+    // Values for __slots__, set by a (dict, slots) BUILD state.
+    slots: HashMap<String, Value<'a>>,
+    // BUILD state that is neither a dict nor a (dict, slots) pair,
+    // meant for the class' own __setstate__.
+    state: Option<Box<Value<'a>>>,
+    pub args: Vec<Value<'a>>,
+    pub kwargs: Option<HashMap<String, Value<'a>>>,
 }
 
-impl Instance {
+impl<'a> Instance<'a> {
     pub fn new(name: String, module: String) -> Self {
         Instance {
             name,
             module,
             fields: HashMap::new(),
+            other_fields: HashMap::new(),
+            slots: HashMap::new(),
+            state: None,
             args: Vec::new(),
             kwargs: None,
         }
@@ -118,29 +234,339 @@ impl Instance {
         format!("{}.{}", self.module, self.name)
     }
 
-    pub fn set_fields(&mut self, new_fields: HashMap<Value, Value>) {
+    pub fn fields(&self) -> &HashMap<String, Value<'a>> {
+        &self.fields
+    }
+
+    pub fn other_fields(&self) -> &HashMap<Value<'a>, Value<'a>> {
+        &self.other_fields
+    }
+
+    pub fn slots(&self) -> &HashMap<String, Value<'a>> {
+        &self.slots
+    }
+
+    pub fn state(&self) -> Option<&Value<'a>> {
+        self.state.as_deref()
+    }
+
+    // Like __dict__.update, which takes keys of any type.
+    pub fn set_fields(&mut self, new_fields: HashMap<Value<'a>, Value<'a>>) {
         for (k, v) in new_fields {
-            if let Value::String(k) = k {
-                self.fields.insert(k, v);
-            } else {
-                panic!("tried using a non string for an obj field name");
+            match k {
+                Value::String(k) => self.fields.insert(k, v),
+                k => self.other_fields.insert(k, v),
+            };
+        }
+    }
+
+    // Like setattr, which only takes str names.
+    pub fn set_slots(
+        &mut self,
+        new_slots: HashMap<Value<'a>, Value<'a>>,
+    ) -> Result<(), PickleError> {
+        insert_attributes(&mut self.slots, new_slots)
+    }
+
+    // Apply a BUILD state the way object.__setstate__ does: a dict
+    // updates the fields and a (dict or None, slots) pair sets both.
+    // Any other state is kept as is, since only the class' own
+    // __setstate__ would know what to do with it.
+    pub fn set_state(&mut self, state: Value<'a>) -> Result<(), PickleError> {
+        match state {
+            Value::None => Ok(()),
+            Value::Dict(fields) => {
+                self.set_fields(fields);
+                Ok(())
+            }
+            Value::Tuple(pair)
+                if pair.len() == 2
+                    && matches!(pair[0], Value::Dict(_) | Value::None)
+                    && matches!(pair[1], Value::Dict(_) | Value::None) =>
+            {
+                let mut pair = pair.into_iter();
+                if let Some(Value::Dict(fields)) = pair.next() {
+                    self.set_fields(fields);
+                }
+                if let Some(Value::Dict(slots)) = pair.next() {
+                    self.set_slots(slots)?;
+                }
+                Ok(())
+            }
+            state => {
+                self.state = Some(Box::new(state));
+                Ok(())
             }
         }
     }
 
     pub fn fields_to_string(&self) -> String {
-        let mut vec: Vec<_> = self.fields.iter().collect();
-        vec.sort_by_key(|&(k, _)| k.clone());
-        let s = vec
+        self.fields_string(Scope {
+            heap: &Heap::tree(),
+            depth: 0,
+        })
+    }
+
+    pub fn slots_to_string(&self) -> String {
+        attributes_string(
+            &self.slots,
+            Scope {
+                heap: &Heap::tree(),
+                depth: 0,
+            },
+        )
+    }
+
+    fn fields_string(&self, scope: Scope<'_>) -> String {
+        let mut others: Vec<_> = self
+            .other_fields
             .iter()
-            .map(|(k, v)| format!("{k}: {v}"))
-            .collect::<Vec<String>>()
-            .join(", ");
-        s
+            .map(|(k, v)| format!("{}: {}", Shown(k, scope), Shown(v, scope)))
+            .collect();
+        others.sort();
+        std::iter::once(attributes_string(&self.fields, scope))
+            .filter(|fields| !fields.is_empty())
+            .chain(others)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn insert_attributes<'a>(
+    attributes: &mut HashMap<String, Value<'a>>,
+    new_attributes: HashMap<Value<'a>, Value<'a>>,
+) -> Result<(), PickleError> {
+    for (k, v) in new_attributes {
+        if let Value::String(k) = k {
+            attributes.insert(k, v);
+        } else {
+            return Err(PickleError::TypeMismatch {
+                expected: "str attribute name",
+                found: k.type_name(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn attributes_string(attributes: &HashMap<String, Value>, scope: Scope<'_>) -> String {
+    let mut vec: Vec<_> = attributes.iter().collect();
+    vec.sort_by_key(|&(k, _)| k.clone());
+    vec.iter()
+        .map(|(k, v)| format!("{k}: {}", Shown(v, scope)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Result of `Parser::parse_graph`. Memoized lists, dicts, sets and
+// objects are kept once in the heap, and the values holding them have a
+// `Value::Ref` to their slot instead, so aliasing and cycles survive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Graph<'a> {
+    root: Value<'a>,
+    heap: Vec<Value<'a>>,
+    // Nesting limit of the parse. Each value was checked on its own, so
+    // following references is checked against it too.
+    max_nesting: usize,
+    // Copy limit of the parse, which copying referenced values out of
+    // the heap counts against, like copies out of the memo.
+    max_copied_values: u64,
+}
+
+impl<'a> Graph<'a> {
+    pub(crate) fn new(root: Value<'a>, heap: Vec<Value<'a>>, limits: Limits) -> Self {
+        Graph {
+            root,
+            heap,
+            max_nesting: limits.max_nesting,
+            max_copied_values: limits.max_copied_values,
+        }
+    }
+
+    // The value the pickle returned.
+    pub fn root(&self) -> &Value<'a> {
+        &self.root
+    }
+
+    // The value a `Value::Ref` refers to.
+    pub fn get(&self, id: usize) -> Option<&Value<'a>> {
+        self.heap.get(id)
+    }
+
+    // The value `value` refers to if it is a reference, else itself.
+    pub fn resolve<'g>(&'g self, value: &'g Value<'a>) -> &'g Value<'a> {
+        match value {
+            Value::Ref(id) => self.get(*id).unwrap_or(value),
+            value => value,
+        }
+    }
+
+    // Copy the graph into a plain tree, replacing each reference with a
+    // copy of its value. Fails on cycles, which a tree can't hold, and
+    // on trees nested deeper or copying more than the parse allowed.
+    pub fn materialize(&self) -> Result<Value<'a>, PickleError> {
+        // Copies are built bottom up with a stack of tasks rather than by
+        // recursion, so deep graphs don't overflow the call stack.
+        enum Task<'g, 'a> {
+            // Push a copy of a value at some depth to `copies`.
+            Copy(&'g Value<'a>, usize),
+            // Replace the copies of the children of a container with a
+            // copy of the container.
+            Build(&'g Value<'a>, usize),
+            // Done copying a reference, which may be copied again.
+            Leave(usize),
+        }
+
+        let mut tasks = vec![Task::Copy(&self.root, 0)];
+        let mut copies = Vec::new();
+        let mut path = HashSet::new();
+        let mut copied = 0;
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Copy(Value::Ref(id), depth) => {
+                    if !path.insert(*id) {
+                        return Err(PickleError::Cycle);
+                    }
+                    tasks.push(Task::Leave(*id));
+                    tasks.push(Task::Copy(&self.heap[*id], depth));
+                }
+                Task::Copy(value, depth) => {
+                    // Values inside a reference are copied out of the heap.
+                    if !path.is_empty() {
+                        copied += 1;
+                        if copied > self.max_copied_values {
+                            return Err(PickleError::LimitExceeded {
+                                limit: "copied values",
+                                value: copied,
+                                max: self.max_copied_values,
+                            });
+                        }
+                    }
+                    match children(value) {
+                        Some(children) => {
+                            let depth = self.nested(depth)?;
+                            let children: Vec<_> = children.collect();
+                            tasks.push(Task::Build(value, children.len()));
+                            tasks.extend(
+                                children
+                                    .into_iter()
+                                    .rev()
+                                    .map(|child| Task::Copy(child, depth)),
+                            );
+                        }
+                        None => copies.push(value.clone()),
+                    }
+                }
+                Task::Build(value, count) => {
+                    let children = copies.split_off(copies.len() - count);
+                    copies.push(rebuild(value, children));
+                }
+                Task::Leave(id) => {
+                    path.remove(&id);
+                }
+            }
+        }
+        Ok(copies.pop().unwrap_or(Value::None))
+    }
+
+    // Depth of the values inside a container at `depth`, if the tree
+    // may hold them.
+    fn nested(&self, depth: usize) -> Result<usize, PickleError> {
+        if depth >= self.max_nesting {
+            return Err(PickleError::LimitExceeded {
+                limit: "nesting depth",
+                value: depth as u64 + 1,
+                max: self.max_nesting as u64,
+            });
+        }
+        Ok(depth + 1)
+    }
+}
+
+// Copy of a container whose children were copied to `copies`, in the
+// order `children` gives them.
+fn rebuild<'a>(value: &Value<'a>, copies: Vec<Value<'a>>) -> Value<'a> {
+    let mut copies = copies.into_iter();
+    match value {
+        Value::Tuple(_) => Value::Tuple(copies.collect()),
+        Value::List(_) => Value::List(copies.collect()),
+        Value::Dict(_) => Value::Dict(pairs(copies)),
+        Value::Set(_) => Value::Set(copies.collect()),
+        Value::FrozenSet(_) => Value::FrozenSet(copies.collect()),
+        Value::Object(inst) => Value::Object(rebuild_instance(inst, &mut copies)),
+        Value::Callable(inst, _) => {
+            let inst = rebuild_instance(inst, &mut copies);
+            Value::Callable(inst, Box::new(copies.next().unwrap_or(Value::None)))
+        }
+        Value::PersistentId(_) => {
+            Value::PersistentId(Box::new(copies.next().unwrap_or(Value::None)))
+        }
+        value => value.clone(),
+    }
+}
+
+fn rebuild_instance<'a>(
+    inst: &Instance<'a>,
+    copies: &mut impl Iterator<Item = Value<'a>>,
+) -> Instance<'a> {
+    Instance {
+        name: inst.name.clone(),
+        module: inst.module.clone(),
+        fields: inst.fields.keys().cloned().zip(copies.by_ref()).collect(),
+        other_fields: pairs(copies.by_ref().take(2 * inst.other_fields.len())),
+        slots: inst.slots.keys().cloned().zip(copies.by_ref()).collect(),
+        state: inst
+            .state
+            .as_ref()
+            .and_then(|_| copies.next())
+            .map(Box::new),
+        args: copies.by_ref().take(inst.args.len()).collect(),
+        kwargs: inst
+            .kwargs
+            .as_ref()
+            .map(|kwargs| kwargs.keys().cloned().zip(copies.by_ref()).collect()),
     }
 }
 
-impl PartialEq for Value {
+// Map of keys and values that alternate in `copies`.
+fn pairs<'a>(mut copies: impl Iterator<Item = Value<'a>>) -> HashMap<Value<'a>, Value<'a>> {
+    std::iter::from_fn(|| Some((copies.next()?, copies.next()?))).collect()
+}
+
+// Values held by a container or object, if `value` is one.
+pub(crate) fn children<'v, 'a>(
+    value: &'v Value<'a>,
+) -> Option<Box<dyn Iterator<Item = &'v Value<'a>> + 'v>> {
+    Some(match value {
+        Value::Tuple(items) | Value::List(items) => Box::new(items.iter()),
+        Value::Dict(map) => Box::new(map.iter().flat_map(|(k, v)| [k, v])),
+        Value::Set(set) | Value::FrozenSet(set) => Box::new(set.iter()),
+        Value::Object(inst) => Box::new(instance_values(inst)),
+        Value::Callable(inst, args) => {
+            Box::new(instance_values(inst).chain(std::iter::once(&**args)))
+        }
+        Value::PersistentId(pid) => Box::new(std::iter::once(&**pid)),
+        _ => return None,
+    })
+}
+
+fn instance_values<'v, 'a>(inst: &'v Instance<'a>) -> impl Iterator<Item = &'v Value<'a>> {
+    inst.fields()
+        .values()
+        .chain(inst.other_fields().iter().flat_map(|(k, v)| [k, v]))
+        .chain(inst.slots().values())
+        .chain(inst.state())
+        .chain(inst.args.iter())
+        .chain(inst.kwargs.iter().flat_map(|kwargs| kwargs.values()))
+}
+
+impl Display for Graph<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        in_heap(&self.root, &self.heap, self.max_nesting).fmt(f)
+    }
+}
+
+impl PartialEq for Value<'_> {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Value::Bool(a), Value::Bool(b)) => a == b,
@@ -149,107 +575,340 @@ impl PartialEq for Value {
             (Value::UInt(a), Value::UInt(b)) => a == b,
             (Value::Long(a), Value::Long(b)) => a == b,
             (Value::ULong(a), Value::ULong(b)) => a == b,
+            (Value::BigInt(a), Value::BigInt(b)) => a == b,
             (Value::Float(a), Value::Float(b)) => a == b,
-            (Value::Tuple(a), Value::Tuple(b)) => {
-                if a.len() == b.len() {
-                    self.to_string() == other.to_string()
-                } else {
-                    false
-                }
+            (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => {
+                self.to_string() == other.to_string()
             }
-            (Value::List(a), Value::List(b)) => {
-                if a.len() == b.len() {
-                    self.to_string() == other.to_string()
-                } else {
-                    false
-                }
+            (Value::List(a), Value::List(b)) if a.len() == b.len() => {
+                self.to_string() == other.to_string()
             }
-            (Value::Dict(a), Value::Dict(b)) => {
-                if a.len() == b.len() {
-                    self.to_string() == other.to_string()
-                } else {
-                    false
-                }
+            (Value::Dict(a), Value::Dict(b)) if a.len() == b.len() => {
+                self.to_string() == other.to_string()
             }
-            (Value::Bytes(a), Value::Bytes(b)) => {
-                if a.len() == b.len() {
-                    self.to_string() == other.to_string()
-                } else {
-                    false
-                }
+            (Value::Set(a), Value::Set(b)) => a == b,
+            (Value::FrozenSet(a), Value::FrozenSet(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) if a.len() == b.len() => {
+                self.to_string() == other.to_string()
             }
+            (Value::ByteArray(a), Value::ByteArray(b)) => a == b,
             (Value::Callable(f1, arg1), Value::Callable(f2, arg2)) => *f1 == *f2 && arg1 == arg2,
-            (Value::Mark, Value::Mark) => true,
+            (Value::PersistentId(a), Value::PersistentId(b)) => a == b,
+            (
+                Value::PickleBuffer { data: a, readonly: ra },
+                Value::PickleBuffer { data: b, readonly: rb },
+            ) => a == b && ra == rb,
+            (Value::Ref(a), Value::Ref(b)) => a == b,
             (Value::None, Value::None) => true,
             _ => false,
         }
     }
 }
 
-impl Eq for Value {}
+impl Eq for Value<'_> {}
 
-impl std::hash::Hash for Value {
+impl std::hash::Hash for Value<'_> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.to_string().hash(state);
     }
 }
 
-impl Display for Value {
+impl Display for Value<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Bool(v) => write!(f, "{}", if *v { "True" } else { "False" }),
-            Value::String(s) => write!(f, "'{s}'"),
-            Value::Int(v) => write!(f, "{v}"),
-            Value::UInt(v) => write!(f, "{v}"),
-            Value::Long(v) => write!(f, "{v}"),
-            Value::ULong(v) => write!(f, "{v}"),
-            Value::Float(v) => write!(f, "{v:.1}"),
-            Value::Tuple(v) => {
-                let s = v
-                    .iter()
-                    .map(|i| i.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                write!(f, "({s})")
-            }
-            Value::Dict(v) => {
-                let mut vec: Vec<_> = v.iter().collect();
-                vec.sort_by_key(|&(k, _)| k.to_string().clone());
-                let s = vec
-                    .iter()
-                    .map(|(k, v)| format!("{k}: {v}"))
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                write!(f, "{{{s}}}")
-            }
-            Value::List(v) => {
-                let s = v
-                    .iter()
-                    .map(|i| i.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                write!(f, "[{s}]")
-            }
-            Value::Bytes(v) => {
-                let s = v
-                    .iter()
-                    .map(|i| format!("{i:x}"))
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                write!(f, "[{s}]")
-            }
-            Value::Object(inst) => write!(
+        let heap = Heap::tree();
+        write_value(
+            f,
+            self,
+            Scope {
+                heap: &heap,
+                depth: 0,
+            },
+        )
+    }
+}
+
+// Display a value of the graph whose heap is `heap`, following its
+// references down to `max_depth` containers.
+pub(crate) fn in_heap<'v>(
+    value: &'v Value<'v>,
+    heap: &'v [Value<'v>],
+    max_depth: usize,
+) -> impl Display + 'v {
+    struct InHeap<'v>(&'v Value<'v>, &'v [Value<'v>], usize);
+
+    impl Display for InHeap<'_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let heap = Heap {
+                values: self.1,
+                path: RefCell::new(HashSet::new()),
+                max_depth: self.2,
+            };
+            write_value(
                 f,
-                "<{} object at {:p}> (fields: {:?}, args: {:?}, kwargs: {:?})",
-                inst.as_key(),
-                inst,
-                inst.fields_to_string(),
-                inst.args,
-                inst.kwargs
-            ),
-            Value::Callable(inst, arg) => write!(f, "*{}({})", inst.as_key(), arg),
-            Value::Mark => write!(f, "Mark"),
-            Value::None => write!(f, "None"),
+                self.0,
+                Scope {
+                    heap: &heap,
+                    depth: 0,
+                },
+            )
         }
     }
+
+    InHeap(value, heap, max_depth)
+}
+
+// Where the values being written live. References are followed through
+// `values`, except to values already being written further up in
+// `path`, which would repeat forever, and past `max_depth` containers.
+// Outside of a graph there are no values and references are written as
+// `<ref N>`.
+struct Heap<'h> {
+    values: &'h [Value<'h>],
+    path: RefCell<HashSet<usize>>,
+    max_depth: usize,
+}
+
+impl Heap<'_> {
+    fn tree() -> Self {
+        Heap {
+            values: &[],
+            path: RefCell::new(HashSet::new()),
+            max_depth: usize::MAX,
+        }
+    }
+}
+
+// Kept to two words, as it is passed down every level of a value.
+#[derive(Clone, Copy)]
+struct Scope<'h> {
+    heap: &'h Heap<'h>,
+    depth: usize,
+}
+
+impl Scope<'_> {
+    // Scope of the values inside a container.
+    fn nested(self) -> Self {
+        Scope {
+            depth: self.depth + 1,
+            ..self
+        }
+    }
+}
+
+// A value written in a scope, for the helpers that build strings.
+struct Shown<'v, 'h>(&'v Value<'v>, Scope<'h>);
+
+impl Display for Shown<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_value(f, self.0, self.1)
+    }
+}
+
+// Write `value`, or what it refers to.
+fn write_value(
+    f: &mut std::fmt::Formatter<'_>,
+    value: &Value,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    match value {
+        Value::Ref(id) => write_ref(f, *id, scope),
+        value if scope.depth >= scope.heap.max_depth && is_nested(value) => write_elided(f, value),
+        value => write_plain(f, value, scope),
+    }
+}
+
+fn write_ref(f: &mut std::fmt::Formatter<'_>, id: usize, scope: Scope<'_>) -> std::fmt::Result {
+    let Some(target) = scope.heap.values.get(id) else {
+        return write!(f, "<ref {id}>");
+    };
+    if scope.depth >= scope.heap.max_depth || !scope.heap.path.borrow_mut().insert(id) {
+        return write_elided(f, target);
+    }
+    let result = write_plain(f, target, scope);
+    scope.heap.path.borrow_mut().remove(&id);
+    result
+}
+
+// Containers go through calls only, and everything else through
+// `write_leaf`, so that each level of a nested value takes little stack.
+fn write_plain(
+    f: &mut std::fmt::Formatter<'_>,
+    value: &Value,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    match value {
+        Value::Tuple(v) => write_sequence(f, "(", v, ")", scope.nested()),
+        Value::List(v) => write_sequence(f, "[", v, "]", scope.nested()),
+        Value::Dict(v) => write_dict(f, v, scope.nested()),
+        Value::Set(v) => write_set(f, v, false, scope.nested()),
+        Value::FrozenSet(v) => write_set(f, v, true, scope.nested()),
+        Value::Object(inst) => write_object(f, inst, scope.nested()),
+        Value::Callable(inst, arg) => write_call(f, inst, arg, scope.nested()),
+        Value::PersistentId(pid) => write_persistent_id(f, pid, scope.nested()),
+        value => write_leaf(f, value),
+    }
+}
+
+fn write_leaf(f: &mut std::fmt::Formatter<'_>, value: &Value) -> std::fmt::Result {
+    match value {
+        Value::Bool(v) => write!(f, "{}", if *v { "True" } else { "False" }),
+        Value::String(s) => write!(f, "'{s}'"),
+        Value::Int(v) => write!(f, "{v}"),
+        Value::UInt(v) => write!(f, "{v}"),
+        Value::Long(v) => write!(f, "{v}"),
+        Value::ULong(v) => write!(f, "{v}"),
+        Value::BigInt(v) => write!(f, "{v}"),
+        Value::Float(v) => write!(f, "{v:.1}"),
+        Value::Bytes(v) => write!(f, "[{}]", hex_list(v)),
+        Value::ByteArray(v) => write!(f, "bytearray([{}])", hex_list(v)),
+        Value::PickleBuffer { data, readonly } => {
+            let flag = if *readonly { ", readonly" } else { "" };
+            write!(f, "PickleBuffer([{}]{flag})", hex_list(data))
+        }
+        Value::Ref(id) => write!(f, "<ref {id}>"),
+        Value::None => write!(f, "None"),
+        value => write_elided(f, value),
+    }
+}
+
+fn write_dict(
+    f: &mut std::fmt::Formatter<'_>,
+    map: &HashMap<Value, Value>,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    let mut vec: Vec<_> = map
+        .iter()
+        .map(|(k, v)| (Shown(k, scope).to_string(), v))
+        .collect();
+    vec.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let s = vec
+        .iter()
+        .map(|(k, v)| format!("{k}: {}", Shown(v, scope)))
+        .collect::<Vec<String>>()
+        .join(", ");
+    write!(f, "{{{s}}}")
+}
+
+fn write_object(
+    f: &mut std::fmt::Formatter<'_>,
+    inst: &Instance,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    write!(
+        f,
+        "<{} object at {:p}> (fields: {:?}, slots: {:?}, state: ",
+        inst.as_key(),
+        inst,
+        inst.fields_string(scope),
+        attributes_string(&inst.slots, scope),
+    )?;
+    match &inst.state {
+        Some(state) => write_value(f, state, scope)?,
+        None => write!(f, "None")?,
+    }
+    write!(f, ", args: [")?;
+    write_items(f, &inst.args, scope)?;
+    match &inst.kwargs {
+        Some(kwargs) => write!(f, "], kwargs: {{{}}})", attributes_string(kwargs, scope)),
+        None => write!(f, "], kwargs: None)"),
+    }
+}
+
+// Values that hold other values.
+fn is_nested(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Tuple(_)
+            | Value::List(_)
+            | Value::Dict(_)
+            | Value::Set(_)
+            | Value::FrozenSet(_)
+            | Value::Object(_)
+            | Value::Callable(_, _)
+            | Value::PersistentId(_)
+    )
+}
+
+// A value that contains itself shows the inner reference as `[...]`,
+// like Python's repr. So do values nested too deep to show.
+fn write_elided(f: &mut std::fmt::Formatter<'_>, value: &Value) -> std::fmt::Result {
+    match value {
+        Value::List(_) => write!(f, "[...]"),
+        Value::Dict(_) => write!(f, "{{...}}"),
+        _ => write!(f, "..."),
+    }
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|i| format!("{i:x}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn write_sequence(
+    f: &mut std::fmt::Formatter<'_>,
+    open: &str,
+    items: &[Value],
+    close: &str,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    f.write_str(open)?;
+    write_items(f, items, scope)?;
+    f.write_str(close)
+}
+
+// Write items straight to the formatter rather than through
+// intermediate strings, which keeps deeply nested values cheap.
+fn write_items(
+    f: &mut std::fmt::Formatter<'_>,
+    items: &[Value],
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write_value(f, item, scope)?;
+    }
+    Ok(())
+}
+
+// Sets are unordered, so sort items like Dict keys to get a stable repr.
+fn write_set(
+    f: &mut std::fmt::Formatter<'_>,
+    set: &HashSet<Value>,
+    frozen: bool,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    let mut items: Vec<String> = set.iter().map(|v| Shown(v, scope).to_string()).collect();
+    items.sort();
+    match (frozen, items.is_empty()) {
+        (false, true) => write!(f, "set()"),
+        (false, false) => write!(f, "{{{}}}", items.join(", ")),
+        (true, true) => write!(f, "frozenset()"),
+        (true, false) => write!(f, "frozenset({{{}}})", items.join(", ")),
+    }
+}
+
+fn write_call(
+    f: &mut std::fmt::Formatter<'_>,
+    inst: &Instance,
+    arg: &Value,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    write!(f, "*{}(", inst.as_key())?;
+    write_value(f, arg, scope)?;
+    f.write_str(")")
+}
+
+fn write_persistent_id(
+    f: &mut std::fmt::Formatter<'_>,
+    pid: &Value,
+    scope: Scope<'_>,
+) -> std::fmt::Result {
+    f.write_str("persistent_id(")?;
+    write_value(f, pid, scope)?;
+    f.write_str(")")
 }
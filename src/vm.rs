@@ -1,11 +1,16 @@
-use std::collections::HashMap;
-use std::io::Read;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read};
 
+use crate::bigint::BigInt;
+use crate::codec;
+use crate::error::PickleError;
 use crate::op::*;
+use crate::policy::SafetyPolicy;
 
-use crate::value::{Instance, Value};
+use crate::value::{children, in_heap, Graph, Instance, Value};
 
-pub struct VM<'a> {
+pub struct VM<'a, 'b> {
     // Entire Program.
     reader: &'a mut dyn Read,
     // Either current frame or entire pickle file.
@@ -15,432 +20,1422 @@ pub struct VM<'a> {
     // Protocol version;
     version: u8,
     // Value stack.
-    stack: Vec<Value>,
-    // VM memory.
-    memo: Vec<Value>,
+    stack: Vec<Value<'b>>,
+    // Nesting depth of each value on the stack, worked out from the
+    // values it was built from, so checking the limit never walks one.
+    depths: Vec<usize>,
+    // Deepest value popped by the opcode being executed.
+    popped_depth: usize,
+    // Stack length at each MARK, like CPython's metastack.
+    marks: Vec<usize>,
+    // VM memory, with the depth of each value. Keyed by index since
+    // PUT indices can be sparse or out of order.
+    memo: HashMap<usize, (Value<'b>, usize)>,
     // Set if parsing a framed stream.
     is_framed: bool,
+    // Bytes pulled from the reader so far.
+    consumed: u64,
+    // Set once the PROTO header has been consumed.
+    started: bool,
+    // First opcode of a stream without PROTO header, put back
+    // after peeking for the header.
+    peeked: Option<u8>,
+    // Resource limits for untrusted streams.
+    limits: Limits,
+    // Opcodes decoded so far.
+    ops: u64,
+    // Values copied by memo PUTs and GETs so far.
+    copied_values: u64,
     // Extensions. Used to define replacemnt for python functions.
-    extensions: HashMap<String, Extension>
+    extensions: HashMap<String, Extension<'a, 'b>>,
+    // copyreg extension registry, code -> (module, name). Read by EXT1, EXT2 and EXT4.
+    extension_codes: HashMap<u32, (String, String)>,
+    // Resolves persistent ids, like `Unpickler.persistent_load`.
+    persistent_load: Option<PersistentLoad<'a, 'b>>,
+    // Out-of-band buffers left for NEXT_BUFFER, in order.
+    buffers: VecDeque<Cow<'b, [u8]>>,
+    // Warnings emitted by extensions while parsing this stream.
+    warnings: Vec<String>,
+    // Globals the stream is allowed to reference.
+    policy: SafetyPolicy,
+    // Memoize mutable values in the heap instead of copying them.
+    object_graph: bool,
+    // Values referenced by `Value::Ref` in object graph mode.
+    heap: Vec<Value<'b>>,
+    // Skip bytes payloads instead of reading them, leaving the
+    // values empty. Set by the scanner, which never looks at them.
+    skip_payloads: bool,
+    // Snapshots of the values popped by the opcode being executed, in
+    // the order they were popped, so errors show the stack it found.
+    popped: Vec<String>,
 }
 
-pub type Extension = fn(Value) -> Value;
+// Replacement for a python callable. Gets the call arguments and
+// returns the value the call would have produced.
+pub type Extension<'a, 'b> =
+    Box<dyn FnMut(&mut ExtensionContext<'_, 'b>, Value<'b>) -> Result<Value<'b>, PickleError> + 'a>;
 
-impl<'a> VM<'a> {
-    // Init the VM by reading the first OP of the buffer
-    // which should set the Protocol verison.
+// What an extension can see of the parser while it runs.
+pub struct ExtensionContext<'c, 'b> {
+    instance: &'c Instance<'b>,
+    kwargs: Option<&'c HashMap<String, Value<'b>>>,
+    heap: &'c [Value<'b>],
+    version: u8,
+    limits: Limits,
+    warnings: &'c mut Vec<String>,
+}
+
+impl<'b> ExtensionContext<'_, 'b> {
+    // The callable the extension was registered for.
+    pub fn instance(&self) -> &Instance<'b> {
+        self.instance
+    }
+
+    // Keyword arguments of a NEWOBJ_EX call, if any.
+    pub fn kwargs(&self) -> Option<&HashMap<String, Value<'b>>> {
+        self.kwargs
+    }
+
+    // The value a `Value::Ref` refers to, when parsing an object graph.
+    pub fn get(&self, id: usize) -> Option<&Value<'b>> {
+        self.heap.get(id)
+    }
+
+    // Protocol version of the stream being parsed.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    // Record a warning, available from `Parser::warnings` once done.
+    pub fn warn(&mut self, message: impl Into<String>) {
+        self.warnings.push(message.into());
+    }
+}
+
+// Gets the pid read by PERSID or popped by BINPERSID and returns the
+// object it refers to.
+pub type PersistentLoad<'a, 'b> = Box<dyn FnMut(Value<'b>) -> Result<Value<'b>, PickleError> + 'a>;
+
+// Rust can't allocate more than isize::MAX bytes anyway.
+pub const DEFAULT_MAX_LENGTH: u64 = isize::MAX as u64;
+
+// Bounds on what a stream may make the parser do. Lengths read from
+// the stream are never trusted to allocate upfront. The defaults are
+// finite, so that `Parser::parse` on untrusted input can't use memory
+// or time out of proportion with the stream, but generous enough for
+// large real pickles. Raise them with `Parser::set_limits` for bigger
+// ones, and lower them for streams that should be small.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // Largest FRAME size.
+    pub max_frame_size: u64,
+    // Largest length prefixed argument (bytes, strings, ints).
+    pub max_length: u64,
+    // Bytes read from the stream, frames included.
+    pub max_total_bytes: u64,
+    // Values on the stack, and marks on the metastack.
+    pub max_stack_depth: usize,
+    pub max_memo_entries: usize,
+    // Containers nested in each other, objects included.
+    pub max_nesting: usize,
+    // Opcodes decoded, STOP included.
+    pub max_ops: u64,
+    // Values copied into and out of the memo by PUTs and GETs, counting
+    // every value in a copied container. Bounds pickles that double a
+    // value with each GET.
+    pub max_copied_values: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frame_size: DEFAULT_MAX_LENGTH,
+            max_length: DEFAULT_MAX_LENGTH,
+            max_total_bytes: 1 << 32,
+            max_stack_depth: usize::MAX,
+            // Python memoizes every str and container, so a pickle of
+            // millions of them has as many entries.
+            max_memo_entries: 1 << 24,
+            // Python's default recursion limit.
+            max_nesting: 1000,
+            max_ops: u64::MAX,
+            max_copied_values: 1 << 24,
+        }
+    }
+}
+
+impl<'a, 'b> VM<'a, 'b> {
+    // Nothing is read until the first step, so building
+    // a VM never fails.
     pub fn from(r: &'a mut dyn Read) -> Self {
-        let mut vm = VM {
+        VM {
             reader: r,
             version: 0,
             pc: 0,
             working_buffer: Box::new([]),
             stack: Vec::new(),
-            memo: Vec::new(),
+            depths: Vec::new(),
+            popped_depth: 0,
+            marks: Vec::new(),
+            memo: HashMap::new(),
             is_framed: false,
+            consumed: 0,
+            started: false,
+            peeked: None,
+            limits: Limits::default(),
+            ops: 0,
+            copied_values: 0,
             extensions: HashMap::new(),
-        };
-        // Read the first 3 bytes. They should contain
-        // 2 bytes of header + 1 initial OP, which may
-        // or may not be a Frame OP. It can indicate if
-        // the stream is framed though.
-        let mut buf = [0; 2];
-        vm.reader
-            .read_exact(&mut buf)
-            .expect("can't read first 3 bytes");
-        if Op::from(buf[0]) != Op::Proto {
-            panic!("Malformed pickle file. Does not start with 0x80.");
+            extension_codes: HashMap::new(),
+            persistent_load: None,
+            buffers: VecDeque::new(),
+            warnings: Vec::new(),
+            policy: SafetyPolicy::default(),
+            object_graph: false,
+            heap: Vec::new(),
+            skip_payloads: false,
+            popped: Vec::new(),
         }
-        // Check Pickle protocol version.
-        vm.version = match buf[1] {
-            0..=5 => buf[1],
-            _ => panic!("Unrecognized Pickle protocol version"),
-        };
-        vm
+    }
+
+    // Start over on a new stream, keeping extensions and settings.
+    // Out-of-band buffers belong to the previous stream and are dropped.
+    pub fn reset(&mut self, r: &'a mut dyn Read) {
+        let mut vm = VM::from(r);
+        vm.limits = self.limits;
+        vm.extensions = std::mem::take(&mut self.extensions);
+        vm.extension_codes = std::mem::take(&mut self.extension_codes);
+        vm.persistent_load = self.persistent_load.take();
+        vm.policy = std::mem::take(&mut self.policy);
+        vm.object_graph = self.object_graph;
+        vm.skip_payloads = self.skip_payloads;
+        *self = vm;
+    }
+
+    #[inline]
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    #[inline]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Peek the first OP of the buffer, which sets the Protocol
+    // version if it is a PROTO. Protocol 0 and 1 pickles have
+    // no header, so the byte is put back and read as an opcode.
+    fn read_header(&mut self) -> Result<(), PickleError> {
+        self.started = true;
+        let first = self.next_byte()?;
+        if first != PROTO {
+            self.version = 0;
+            self.peeked = Some(first);
+            return Ok(());
+        }
+        self.version = check_version(self.next_byte()?)?;
+        Ok(())
     }
 
     #[inline]
-    pub fn load_extension(&mut self, module: &str, name: &str, ext: Extension) {
+    pub fn load_extension(&mut self, module: &str, name: &str, ext: Extension<'a, 'b>) {
         self.extensions.insert(format!("{}.{}", module, name), ext);
     }
 
+    #[inline]
+    pub fn add_extension_code(&mut self, code: u32, module: &str, name: &str) {
+        self.extension_codes
+            .insert(code, (module.to_string(), name.to_string()));
+    }
+
+    #[inline]
+    pub fn set_persistent_load(&mut self, f: PersistentLoad<'a, 'b>) {
+        self.persistent_load = Some(f);
+    }
+
+    #[inline]
+    pub fn set_buffers(&mut self, buffers: VecDeque<Cow<'b, [u8]>>) {
+        self.buffers = buffers;
+    }
+
+    #[inline]
+    pub fn set_policy(&mut self, policy: SafetyPolicy) {
+        self.policy = policy;
+    }
+
+    #[inline]
+    pub fn set_object_graph(&mut self, object_graph: bool) {
+        self.object_graph = object_graph;
+    }
+
+    #[inline]
+    pub fn set_skip_payloads(&mut self, skip_payloads: bool) {
+        self.skip_payloads = skip_payloads;
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // The heap filled in object graph mode, along with the value the
+    // stream returned. The memo entries referring to the heap go with
+    // it, as the next pickle of the stream starts a new heap.
+    pub fn take_graph(&mut self, root: Value<'b>) -> Graph<'b> {
+        self.memo
+            .retain(|_, (value, _)| !matches!(value, Value::Ref(_)));
+        Graph::new(root, std::mem::take(&mut self.heap), self.limits)
+    }
+
     // If stack has one final entry, pop it!
-    pub fn result(&mut self) -> Result<Value, ()> {
-        if let Some(value) = self.stack.pop() {
-            match value {
-                Value::Mark => return Err(()),
-                _ => return Ok(value),
-            }
+    pub fn result(&mut self) -> Result<Value<'b>, PickleError> {
+        if !self.marks.is_empty() {
+            return Err(PickleError::UnbalancedMarks(self.marks.len()));
         }
-        Err(())
+        self.depths.pop();
+        self.stack.pop().ok_or(PickleError::StackUnderflow)
     }
 
     // Only call this method after an Op::Frame was read.
-    fn set_working_frame(&mut self, frame_size: usize) {
-        let mut buf = vec![0; frame_size];
-        self.reader
-            .read_exact(&mut buf)
-            .unwrap_or_else(|_| panic!("couldn't read {frame_size} from reader"));
+    fn set_working_frame(&mut self, frame_size: u64) -> Result<(), PickleError> {
+        check_limit("frame size", frame_size, self.limits.max_frame_size)?;
+        let buf = self.read_growing(frame_size)?;
         self.working_buffer = buf.into_boxed_slice();
         self.pc = 0;
+        Ok(())
+    }
+
+    // Absolute offset of the next byte in the stream. Inside a
+    // frame `pc` is relative to the frame, so count back from
+    // the end of the bytes already read.
+    pub fn position(&self) -> u64 {
+        if self.is_framed {
+            self.consumed - (self.working_buffer.len() - self.pc) as u64
+        } else {
+            self.consumed - self.peeked.is_some() as u64
+        }
+    }
+
+    // Wrap an error with where it happened and what was on top of the stack.
+    fn with_context(&self, e: PickleError, offset: u64, op: Option<Op>) -> PickleError {
+        let stack = self
+            .popped
+            .iter()
+            .cloned()
+            .chain(self.stack.iter().rev().map(|v| self.snapshot(v)))
+            .take(SNAPSHOT_DEPTH)
+            .collect();
+        PickleError::Context {
+            offset,
+            op,
+            stack,
+            source: Box::new(e),
+        }
+    }
+
+    // Whether the next `n` bytes come from the current frame. Once a
+    // frame is used up reading continues from the stream (PEP 3154),
+    // but a read can't start inside a frame and end past it.
+    fn read_from_frame(&mut self, n: usize) -> Result<bool, PickleError> {
+        if !self.is_framed || n == 0 {
+            return Ok(self.is_framed);
+        }
+        let remaining = self.working_buffer.len() - self.pc;
+        if remaining == 0 {
+            self.is_framed = false;
+            self.working_buffer = Box::new([]);
+            self.pc = 0;
+            return Ok(false);
+        }
+        if remaining < n {
+            return Err(straddles_frame());
+        }
+        Ok(true)
     }
 
-    fn decode(&mut self) -> Result<(Op, Value), ()> {
-        let op = self.next_op();
-        if let Op::Stop = op {
-            return Err(());
+    // Fill `buf` from the current frame, or from the reader
+    // outside of one.
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), PickleError> {
+        if self.read_from_frame(buf.len())? {
+            buf.copy_from_slice(&self.working_buffer[self.pc..self.pc + buf.len()]);
+        } else {
+            let rest = match (self.peeked.take(), buf.split_first_mut()) {
+                (Some(byte), Some((first, rest))) => {
+                    *first = byte;
+                    rest
+                }
+                (peeked, _) => {
+                    self.peeked = peeked;
+                    &mut buf[..]
+                }
+            };
+            self.check_total(rest.len() as u64)?;
+            self.reader.read_exact(rest)?;
+            self.consumed += rest.len() as u64;
         }
-        let arg = self.read_arg(op.clone());
+        self.pc += buf.len();
+        Ok(())
+    }
+
+    fn next_byte(&mut self) -> Result<u8, PickleError> {
+        let [byte] = self.next_bytes::<1>()?;
+        Ok(byte)
+    }
 
-        Ok((op, arg))
+    fn next_bytes<const L: usize>(&mut self) -> Result<[u8; L], PickleError> {
+        let mut buf = [0; L];
+        self.fill(&mut buf)?;
+        Ok(buf)
     }
 
-    fn next_byte(&mut self) -> u8 {
-        let byte = {
-            if self.is_framed {
-                self.working_buffer[self.pc]
-            } else {
-                let mut buf = [0; 1];
-                self.reader.read_exact(&mut buf).expect("prra");
-                buf[0]
+    fn next_op(&mut self) -> Result<Op, PickleError> {
+        Op::try_from(self.next_byte()?)
+    }
+
+    // Read up to the next newline, which is consumed but not returned.
+    fn read_line(&mut self) -> Result<Vec<u8>, PickleError> {
+        if self.is_framed && self.pc < self.working_buffer.len() {
+            let rest = &self.working_buffer[self.pc..];
+            let end = rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .ok_or_else(straddles_frame)?;
+            let line = rest[..end].to_vec();
+            self.pc += end + 1;
+            return Ok(line);
+        }
+        let mut bytes = vec![];
+        loop {
+            let byte = self.next_byte()?;
+            if byte == b'\n' {
+                return Ok(bytes);
             }
+            bytes.push(byte);
+        }
+    }
+
+    // Read `n` bytes straight from the reader. The buffer grows as data
+    // arrives, so a bogus length fails at EOF instead of being allocated.
+    fn read_growing(&mut self, n: u64) -> Result<Vec<u8>, PickleError> {
+        self.check_total(n)?;
+        let mut buf = Vec::new();
+        (&mut self.reader).take(n).read_to_end(&mut buf)?;
+        self.consumed += buf.len() as u64;
+        if (buf.len() as u64) < n {
+            return Err(PickleError::UnexpectedEof);
+        }
+        Ok(buf)
+    }
+
+    fn check_total(&self, n: u64) -> Result<(), PickleError> {
+        let total = self.consumed.saturating_add(n);
+        check_limit("total bytes", total, self.limits.max_total_bytes)
+    }
+
+    // Read a length prefixed argument. The length comes straight from
+    // the stream, so check it before reading anything.
+    pub fn read_n(&mut self, n: u64) -> Result<Vec<u8>, PickleError> {
+        let n = self.check_length(n)?;
+        let buf = if self.read_from_frame(n)? {
+            self.working_buffer[self.pc..self.pc + n].to_vec()
+        } else {
+            let mut buf = Vec::new();
+            if n > 0 {
+                buf.extend(self.peeked.take());
+            }
+            buf.extend(self.read_growing((n - buf.len()) as u64)?);
+            buf
         };
-        self.pc += 1;
-        byte
+        self.pc += n;
+        Ok(buf)
     }
 
-    fn next_bytes<const L: usize>(&mut self) -> [u8; L] {
-        let bytes: [u8; L] = {
-            if self.is_framed {
-                self.working_buffer[self.pc..self.pc + L]
-                .try_into()
-                .unwrap()
-            } else {
-                let mut buf = [0; L];
-                self.reader.read_exact(&mut buf).expect("prra");
-                buf
+    // Like `read_n`, but the bytes go to a sink so nothing is allocated.
+    fn skip_n(&mut self, n: u64) -> Result<(), PickleError> {
+        let n = self.check_length(n)?;
+        if !self.read_from_frame(n)? && n > 0 {
+            let rest = (n - self.peeked.take().is_some() as usize) as u64;
+            self.check_total(rest)?;
+            let skipped = io::copy(&mut (&mut self.reader).take(rest), &mut io::sink())?;
+            self.consumed += skipped;
+            if skipped < rest {
+                return Err(PickleError::UnexpectedEof);
             }
+        }
+        self.pc += n;
+        Ok(())
+    }
+
+    // Bytes payloads can be skipped, the other arguments are needed
+    // to follow the stream.
+    fn read_payload(&mut self, n: u64) -> Result<Vec<u8>, PickleError> {
+        if self.skip_payloads {
+            self.skip_n(n)?;
+            return Ok(Vec::new());
+        }
+        self.read_n(n)
+    }
+
+    fn check_length(&self, n: u64) -> Result<usize, PickleError> {
+        check_limit("length", n, self.limits.max_length)?;
+        usize::try_from(n).map_err(|_| PickleError::LimitExceeded {
+            limit: "length",
+            value: n,
+            max: usize::MAX as u64,
+        })
+    }
+
+    // Values below the topmost mark are out of reach until it is popped.
+    fn stack_floor(&self) -> usize {
+        self.marks.last().copied().unwrap_or(0)
+    }
+
+    // Popped values are about to be put in a container, so this is
+    // where nesting is checked.
+    fn pop(&mut self) -> Result<Value<'b>, PickleError> {
+        if self.stack.len() <= self.stack_floor() {
+            return Err(PickleError::StackUnderflow);
+        }
+        let value = self.stack.pop().ok_or(PickleError::StackUnderflow)?;
+        let depth = self.depths.pop().unwrap_or(0);
+        self.record_popped([&value]);
+        self.check_nesting(depth)?;
+        Ok(value)
+    }
+
+    // Pop every value above the topmost mark, in stack order.
+    fn pop_mark(&mut self) -> Result<Vec<Value<'b>>, PickleError> {
+        let mark = self.marks.pop().ok_or(PickleError::StackUnderflow)?;
+        let values = self.stack.split_off(mark);
+        let depths = self.depths.split_off(mark);
+        self.record_popped(values.iter().rev());
+        self.check_nesting(depths.into_iter().max().unwrap_or(0))?;
+        Ok(values)
+    }
+
+    // Drop the values above `len` without using them.
+    fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
+        self.depths.truncate(len);
+    }
+
+    // Push a value built from what the opcode popped: a container is
+    // one deeper than the deepest of them.
+    fn push(&mut self, value: Value<'b>) {
+        let depth = match children(&value) {
+            Some(_) => self.popped_depth + 1,
+            None => 0,
         };
-        self.pc += L;
-        bytes
+        self.push_at(value, depth);
     }
 
-    fn next_op(&mut self) -> Op {
-        Op::from(self.next_byte())
+    fn push_at(&mut self, value: Value<'b>, depth: usize) {
+        self.stack.push(value);
+        self.depths.push(depth);
     }
 
-    pub fn read_n(&mut self, n: usize) -> Vec<u8> {
-        if self.is_framed {
-            let buf = self.working_buffer[self.pc..self.pc + n].into();
-            self.pc += n;
-            buf
-        } else {
-            let mut buf = vec![0; n];
-            self.reader.read_exact(&mut buf.as_mut_slice()).expect("coudlnt read n");
-            buf
+    // Push a value made by user code, such as an extension, which may
+    // be nested any way. It is measured once, as it goes on the stack.
+    fn push_measured(&mut self, value: Value<'b>) {
+        let depth = measure_depth(&value, self.limits.max_nesting);
+        self.push_at(value, depth);
+    }
+
+    // Keep snapshots of the first values an opcode pops, topmost first.
+    fn record_popped<'v>(&mut self, values: impl IntoIterator<Item = &'v Value<'b>>)
+    where
+        'b: 'v,
+    {
+        let room = SNAPSHOT_DEPTH.saturating_sub(self.popped.len());
+        let snapshots: Vec<_> = values
+            .into_iter()
+            .take(room)
+            .map(|v| self.snapshot(v))
+            .collect();
+        self.popped.extend(snapshots);
+    }
+
+    // Display `value` for an error, cut at SNAPSHOT_WIDTH characters.
+    fn snapshot(&self, value: &Value) -> String {
+        snapshot(&in_heap(value, &self.heap, self.limits.max_nesting))
+    }
+
+    // Values popped by an opcode may only go into a container if the
+    // result stays within the nesting limit.
+    fn check_nesting(&mut self, depth: usize) -> Result<(), PickleError> {
+        self.popped_depth = self.popped_depth.max(depth);
+        let max = self.limits.max_nesting;
+        if depth >= max {
+            return Err(PickleError::LimitExceeded {
+                limit: "nesting depth",
+                value: depth as u64 + 1,
+                max: max as u64,
+            });
         }
+        Ok(())
+    }
+
+    fn check_stack_depth(&self) -> Result<(), PickleError> {
+        let depth = self.stack.len().max(self.marks.len());
+        check_limit("stack depth", depth as u64, self.limits.max_stack_depth as u64)
     }
 
-    fn read_arg(&mut self, op: Op) -> Value {
-        match op {
-            Op::AddItems => todo!(),
+    // The top of the stack itself, which may be a reference.
+    fn top_slot(&mut self) -> Result<&mut Value<'b>, PickleError> {
+        if self.stack.len() <= self.stack_floor() {
+            return Err(PickleError::StackUnderflow);
+        }
+        self.stack.last_mut().ok_or(PickleError::StackUnderflow)
+    }
+
+    // The value on top of the stack, for the opcodes that mutate it,
+    // which is at least `depth` deep afterwards. In object graph mode
+    // this may be the value a reference points to, and the reference
+    // itself stays a leaf.
+    fn top_mut(&mut self, depth: usize) -> Result<&mut Value<'b>, PickleError> {
+        self.top_slot()?;
+        if let (Some(top), Some(top_depth)) = (self.stack.last(), self.depths.last_mut()) {
+            if !matches!(top, Value::Ref(_)) {
+                *top_depth = depth.max(*top_depth);
+            }
+        }
+        match self.stack.last_mut() {
+            Some(Value::Ref(id)) => Ok(&mut self.heap[*id]),
+            top => top.ok_or(PickleError::StackUnderflow),
+        }
+    }
+
+    // Count the values a copy of `value` would make, before making it.
+    fn count_copy(&self, value: &Value) -> Result<u64, PickleError> {
+        let budget = self.limits.max_copied_values.saturating_sub(self.copied_values);
+        let copied = self.copied_values + count_values(value, budget);
+        check_limit("copied values", copied, self.limits.max_copied_values)?;
+        Ok(copied)
+    }
+
+    // Push a copy of a memoized value. Shared by GET, BINGET and LONG_BINGET.
+    fn memo_get(&mut self, idx: usize) -> Result<(), PickleError> {
+        let (val, depth) = self.memo.get(&idx).ok_or(PickleError::BadMemoIndex(idx))?;
+        self.copied_values = self.count_copy(val)?;
+        let (val, depth) = (val.clone(), *depth);
+        self.push_at(val, depth);
+        Ok(())
+    }
+
+    // Pop a value the opcode consumes, see `unshare`.
+    fn pop_unshared(&mut self) -> Result<Value<'b>, PickleError> {
+        let value = self.pop()?;
+        self.unshare(value)
+    }
+
+    // The value behind a reference, for the opcodes that consume it.
+    // Anything else may still refer to it, so it is copied. Its depth
+    // is measured along, as it counts like that of a popped value.
+    fn unshare(&mut self, value: Value<'b>) -> Result<Value<'b>, PickleError> {
+        let Value::Ref(id) = value else {
+            return Ok(value);
+        };
+        self.copied_values = self.count_copy(&self.heap[id])?;
+        self.check_nesting(measure_depth(&self.heap[id], self.limits.max_nesting))?;
+        Ok(self.heap[id].clone())
+    }
+
+    // Memoize the top of the stack, replacing whatever was stored
+    // at `idx`. Shared by PUT, BINPUT, LONG_BINPUT and MEMOIZE. In
+    // object graph mode mutable values are moved to the heap first and
+    // referenced from both, so they stay the same value.
+    fn memo_put(&mut self, idx: usize) -> Result<(), PickleError> {
+        if !self.memo.contains_key(&idx) {
+            let entries = self.memo.len() as u64 + 1;
+            check_limit("memo entries", entries, self.limits.max_memo_entries as u64)?;
+        }
+        let (object_graph, id) = (self.object_graph, self.heap.len());
+        let top = self.top_slot()?;
+        if object_graph && is_mutable(top) {
+            let value = std::mem::replace(top, Value::Ref(id));
+            self.heap.push(value);
+            if let Some(depth) = self.depths.last_mut() {
+                *depth = 0;
+            }
+        }
+        // Cloned rather than moved, so it counts as a copy.
+        let top = &self.stack[self.stack.len() - 1];
+        let copied = self.count_copy(top)?;
+        let val = top.clone();
+        self.copied_values = copied;
+        let depth = self.depths.last().copied().unwrap_or(0);
+        self.memo.insert(idx, (val, depth));
+        Ok(())
+    }
+
+    // Resolve a global reference. Every opcode that names a class or
+    // function (GLOBAL, STACK_GLOBAL, INST and EXT*) goes through here.
+    fn find_class(&self, module: String, name: String) -> Result<Instance<'b>, PickleError> {
+        self.policy.check(&module, &name)?;
+        Ok(Instance::new(name, module))
+    }
+
+    // Look up a code in the copyreg extension registry, like
+    // pickle's `Unpickler.get_extension`.
+    fn find_extension(&self, code: i32) -> Result<Instance<'b>, PickleError> {
+        if code <= 0 {
+            return Err(PickleError::Malformed("EXT specifies code <= 0".to_string()));
+        }
+        let (module, name) = self
+            .extension_codes
+            .get(&(code as u32))
+            .cloned()
+            .ok_or(PickleError::UnregisteredExtension(code))?;
+        self.find_class(module, name)
+    }
+
+    // Push the object a persistent id refers to. Without a hook the
+    // pid is kept as is so the stack stays consistent.
+    fn persistent_load(&mut self, pid: Value<'b>) -> Result<(), PickleError> {
+        match self.persistent_load.as_mut() {
+            Some(f) => {
+                let value = f(pid)?;
+                self.push_measured(value);
+            }
+            None => self.push(Value::PersistentId(Box::new(pid))),
+        }
+        Ok(())
+    }
+
+    // Run the extension registered for `inst`, if any. The arguments
+    // are only taken when there is one, so callers can fall back on them.
+    fn call_extension(
+        &mut self,
+        inst: &Instance<'b>,
+        kwargs: Option<&HashMap<String, Value<'b>>>,
+        args: &mut Value<'b>,
+    ) -> Option<Result<Value<'b>, PickleError>> {
+        let ext = self.extensions.get_mut(&inst.as_key())?;
+        let mut ctx = ExtensionContext {
+            instance: inst,
+            kwargs,
+            heap: &self.heap,
+            version: self.version,
+            limits: self.limits,
+            warnings: &mut self.warnings,
+        };
+        Some(ext(&mut ctx, std::mem::replace(args, Value::None)))
+    }
+
+    // Push an instance of a class with the given args, `depth` deep,
+    // letting a registered extension build the value instead, like
+    // REDUCE does. Extensions get the args tuple whatever the opcode,
+    // and keyword arguments through `ExtensionContext::kwargs`.
+    fn instantiate(
+        &mut self,
+        mut inst: Instance<'b>,
+        args: Vec<Value<'b>>,
+        kwargs: Option<HashMap<String, Value<'b>>>,
+        depth: usize,
+    ) -> Result<(), PickleError> {
+        let mut args = Value::Tuple(args);
+        match self.call_extension(&inst, kwargs.as_ref(), &mut args) {
+            Some(result) => self.push_measured(result?),
+            None => {
+                inst.args = args.as_tuple().unwrap();
+                inst.kwargs = kwargs;
+                self.push_at(Value::Object(inst), depth);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_arg(&mut self, op: Op) -> Result<Value<'b>, PickleError> {
+        let arg = match op {
+            Op::AddItems => Value::None,
             Op::Append => Value::None,
             Op::Appends => Value::None,
             Op::BinBytes => {
-                let len = u32::from_le_bytes(self.next_bytes::<4>()) as usize;
-                let bytes = self.read_n(len);
+                let len = u32::from_le_bytes(self.next_bytes::<4>()?);
+                let bytes = self.read_payload(len as u64)?;
                 Value::Bytes(bytes)
             }
-            Op::BinBytes8 => todo!(),
-            Op::BinFloat => Value::Float(f64::from_be_bytes(self.next_bytes::<8>())),
-            Op::BinGet => Value::UInt(self.next_byte() as u32),
-            Op::BinInt => Value::Int(i32::from_le_bytes(self.next_bytes::<4>())),
-            Op::BinInt1 => Value::UInt(self.next_byte() as u32),
-            Op::BinInt2 => Value::UInt(u16::from_le_bytes(self.next_bytes::<2>()) as u32),
+            Op::BinBytes8 => {
+                let len = u64::from_le_bytes(self.next_bytes::<8>()?);
+                Value::Bytes(self.read_payload(len)?)
+            }
+            Op::BinFloat => Value::Float(f64::from_be_bytes(self.next_bytes::<8>()?)),
+            Op::BinGet => Value::UInt(self.next_byte()? as u32),
+            Op::BinInt => Value::Int(i32::from_le_bytes(self.next_bytes::<4>()?)),
+            Op::BinInt1 => Value::UInt(self.next_byte()? as u32),
+            Op::BinInt2 => Value::UInt(u16::from_le_bytes(self.next_bytes::<2>()?) as u32),
             Op::BinString => {
-                let len = i32::from_le_bytes(self.next_bytes::<4>());
-                let s = String::from_utf8(
-                    self.read_n(len as usize)
-                )
-                .expect("meow");
-                Value::String(s)
+                let len = i32::from_le_bytes(self.next_bytes::<4>()?);
+                if len < 0 {
+                    return Err(PickleError::Malformed(format!(
+                        "negative BINSTRING length {len}"
+                    )));
+                }
+                py2_string(self.read_n(len as u64)?)
             }
             Op::BinPersid => Value::None,
             Op::BinUnicode => {
-                let len = u32::from_le_bytes(self.next_bytes::<4>());
-                let s = String::from_utf8(
-                    self.read_n(len as usize)
-                ).expect("wrong string encoding");
+                let len = u32::from_le_bytes(self.next_bytes::<4>()?);
+                let s = String::from_utf8(self.read_n(len as u64)?)?;
                 Value::String(s)
             },
-            Op::BinUnicode8 => todo!(),
-            Op::BinPut => Value::UInt(self.next_byte() as u32),
+            Op::BinUnicode8 => {
+                let len = u64::from_le_bytes(self.next_bytes::<8>()?);
+                Value::String(String::from_utf8(self.read_n(len)?)?)
+            }
+            Op::BinPut => Value::UInt(self.next_byte()? as u32),
             Op::Build => Value::None,
-            Op::ByteArray8 => todo!(),
-            Op::Dict => todo!(),
-            Op::Dup => todo!(),
+            Op::ByteArray8 => {
+                let len = u64::from_le_bytes(self.next_bytes::<8>()?);
+                Value::ByteArray(self.read_payload(len)?)
+            }
+            Op::Dict => Value::None,
+            Op::Dup => Value::None,
             Op::EmptyDict => Value::None,
             Op::EmptyList => Value::None,
-            Op::EmptySet => todo!(),
+            Op::EmptySet => Value::None,
             Op::EmptyTuple => Value::None,
-            Op::Ext1 => todo!(),
-            Op::Ext2 => todo!(),
-            Op::Ext4 => todo!(),
-            Op::Float => todo!(),
-            Op::Frame => Value::ULong(u64::from_le_bytes(self.next_bytes::<8>()) as u128),
-            Op::FrozenSet => todo!(),
-            Op::Get => todo!(),
+            Op::Ext1 => Value::Int(self.next_byte()? as i32),
+            Op::Ext2 => Value::Int(u16::from_le_bytes(self.next_bytes::<2>()?) as i32),
+            Op::Ext4 => Value::Int(i32::from_le_bytes(self.next_bytes::<4>()?)),
+            Op::Float => Value::Float(parse_number(&self.read_line()?)?),
+            Op::Frame => Value::ULong(u64::from_le_bytes(self.next_bytes::<8>()?) as u128),
+            Op::FrozenSet => Value::None,
+            Op::Get => Value::UInt(parse_number(&self.read_line()?)?),
             Op::GlobalOpcode => {
                 let mut bytes = vec![];
                 loop {
-                    let byte = self.next_byte();
+                    let byte = self.next_byte()?;
                     bytes.push(byte);
                     if byte == 0xA {
                         break;
                     }
                 }
                 loop {
-                    let byte = self.next_byte();
+                    let byte = self.next_byte()?;
                     if byte == 0xA {
                         break;
                     }
                     bytes.push(byte);
                 }
-                let s = String::from_utf8(bytes).unwrap();
+                let s = String::from_utf8(bytes)?;
                 Value::String(s)
             },
-            Op::Int => todo!(),
-            Op::Inst => todo!(),
-            Op::List => todo!(),
-            Op::Long => todo!(),
-            Op::Long1 => Value::UInt(self.next_byte() as u32),
-            Op::Long4 => todo!(),
-            Op::LongBinGet => todo!(),
-            Op::LongBinPut => Value::UInt(u32::from_le_bytes(self.next_bytes::<4>())),
+            // Protocol 0 encodes True and False as INT 01 and 00.
+            Op::Int => {
+                let line = self.read_line()?;
+                match line.as_slice() {
+                    b"01" => Value::Bool(true),
+                    b"00" => Value::Bool(false),
+                    _ => match text_int(&line)? {
+                        Value::Long(v) if i32::try_from(v).is_ok() => Value::Int(v as i32),
+                        v => v,
+                    },
+                }
+            }
+            // INST takes the same "module\nname" argument as GLOBAL.
+            Op::Inst => self.read_arg(Op::GlobalOpcode)?,
+            Op::List => Value::None,
+            Op::Long => {
+                let mut line = self.read_line()?;
+                // Python 2 writes longs with a trailing L.
+                if line.last() == Some(&b'L') {
+                    line.pop();
+                }
+                text_int(&line)?
+            }
+            Op::Long1 => {
+                let len = self.next_byte()?;
+                binary_int(&self.read_n(len as u64)?)
+            }
+            Op::Long4 => {
+                let len = i32::from_le_bytes(self.next_bytes::<4>()?);
+                if len < 0 {
+                    return Err(PickleError::Malformed(format!(
+                        "negative LONG4 byte count {len}"
+                    )));
+                }
+                binary_int(&self.read_n(len as u64)?)
+            }
+            Op::LongBinGet => Value::UInt(u32::from_le_bytes(self.next_bytes::<4>()?)),
+            Op::LongBinPut => Value::UInt(u32::from_le_bytes(self.next_bytes::<4>()?)),
             Op::Mark => Value::None,
             Op::Memoize => Value::None,
             Op::NewObj => Value::None,
-            Op::NewObjEx => todo!(),
+            Op::NewObjEx => Value::None,
             Op::NewFalse => Value::None,
             Op::NewTrue => Value::None,
-            Op::NextBuffer => todo!(),
+            Op::NextBuffer => Value::None,
             Op::None => Value::None,
-            Op::Obj => todo!(),
-            Op::Persid => todo!(),
-            Op::Pop => todo!(),
-            Op::PopMark => todo!(),
-            Op::Proto => Value::UInt(self.next_byte() as u32),
-            Op::Put => todo!(),
-            Op::ReadonlyBuffer => todo!(),
+            Op::Obj => Value::None,
+            // Protocol 0 persistent ids are ASCII strings.
+            Op::Persid => {
+                let line = self.read_line()?;
+                if !line.is_ascii() {
+                    return Err(PickleError::Malformed(
+                        "persistent IDs in protocol 0 must be ASCII strings".to_string(),
+                    ));
+                }
+                Value::String(String::from_utf8(line)?)
+            }
+            Op::Pop => Value::None,
+            Op::PopMark => Value::None,
+            Op::Proto => Value::UInt(self.next_byte()? as u32),
+            Op::Put => Value::UInt(parse_number(&self.read_line()?)?),
+            Op::ReadonlyBuffer => Value::None,
             Op::Reduce => Value::None,
-            Op::SetItem => todo!(),
+            Op::SetItem => Value::None,
             Op::SetItems => Value::None,
-            Op::ShortBinbytes => todo!(),
-            Op::ShortBinstring => todo!(),
+            Op::ShortBinbytes => {
+                let len = self.next_byte()?;
+                Value::Bytes(self.read_payload(len as u64)?)
+            }
+            Op::ShortBinstring => {
+                let len = self.next_byte()?;
+                py2_string(self.read_n(len as u64)?)
+            }
             Op::ShortBinunicde => {
-                let len = self.next_byte();
-                let s = String::from_utf8(
-                    self.read_n(len as usize)
-                )
-                .expect("meow");
+                let len = self.next_byte()?;
+                let s = String::from_utf8(self.read_n(len as u64)?)?;
                 Value::String(s)
             }
             Op::StackGlobal => Value::None,
-            Op::Stop => todo!(),
-            Op::String => todo!(),
+            Op::Stop => Value::None,
+            Op::String => {
+                let line = self.read_line()?;
+                // Strip the outermost quotes of the repr.
+                let data = match line.as_slice() {
+                    [q @ (b'\'' | b'"'), data @ .., end] if q == end => data,
+                    _ => {
+                        return Err(PickleError::Malformed(
+                            "the STRING opcode argument must be quoted".to_string(),
+                        ))
+                    }
+                };
+                py2_string(codec::escape_decode(data)?)
+            }
             Op::Tuple => Value::None,
             Op::Tuple1 => Value::None,
             Op::Tuple2 => Value::None,
             Op::Tuple3 => Value::None,
-            Op::Unicode => todo!(),
-        }
+            Op::Unicode => Value::String(codec::raw_unicode_escape_decode(&self.read_line()?)?),
+        };
+        Ok(arg)
     }
 
-    pub fn step(&mut self) -> bool {
-        if let Ok((op, arg)) = self.decode() {
-            match (op, arg.clone()) {
-                (Op::Append, _) => {
-                    let value = self.stack.pop().expect("Must not be empty");
-                    if let Some(Value::List(vec)) = self.stack.last_mut() {
-                        vec.push(value);
-                    } else {
-                        panic!("Stack ordering was wrong");
-                    }
+    // Execute a single instruction. Returns false once STOP is reached.
+    pub fn step(&mut self) -> Result<bool, PickleError> {
+        let (offset, op, arg) = self.next_instruction()?;
+        self.popped.clear();
+        self.popped_depth = 0;
+        self.execute(op.clone(), arg)
+            .and_then(|more| {
+                // The opcode's result is on the stack now.
+                self.popped.clear();
+                self.check_stack_depth()?;
+                Ok(more)
+            })
+            .map_err(|e| self.with_context(e, offset, Some(op)))
+    }
+
+    // Decode the next opcode and its argument without executing it,
+    // along with the opcode's offset. Frames are entered here since
+    // they only affect how the following opcodes are read.
+    pub fn next_instruction(&mut self) -> Result<(u64, Op, Value<'b>), PickleError> {
+        if !self.started {
+            self.read_header()
+                .map_err(|e| self.with_context(e, 0, None))?;
+        }
+        let offset = self.position();
+        self.ops += 1;
+        let op = check_limit("opcode count", self.ops, self.limits.max_ops)
+            .and_then(|_| self.next_op())
+            .map_err(|e| self.with_context(e, offset, None))?;
+        let in_frame = self.is_framed;
+        let arg = self
+            .read_arg(op.clone())
+            .and_then(|arg| {
+                // The frame was dropped while reading the argument.
+                if in_frame && !self.is_framed {
+                    return Err(straddles_frame());
                 }
-                (Op::Appends, _) => {
-                    let mut values = {
-                        let mut values: Vec<Value> = Vec::new();
-                        loop {
-                            let v = self.stack.pop().unwrap();
-                            if v == Value::Mark {
-                                break;
-                            }
-                            values.insert(0, v);
-                        }
-                        values
-                    };
-                    if let Some(Value::List(vec)) = self.stack.last_mut() {
-                        vec.append(&mut values);
-                    } else {
-                        panic!("Stack ordering was wrong");
+                if let (Op::Frame, Value::ULong(frame_size)) = (&op, &arg) {
+                    if self.is_framed && self.pc < self.working_buffer.len() {
+                        return Err(PickleError::Malformed(
+                            "new frame before the end of the current frame".to_string(),
+                        ));
                     }
+                    self.is_framed = true;
+                    self.set_working_frame(*frame_size as u64)?;
                 }
-                (Op::BinBytes, Value::Bytes(_)) => {
-                    self.stack.push(arg);
-                }
-                (Op::BinInt1, Value::UInt(_)) => self.stack.push(arg),
-                (Op::BinInt2, Value::UInt(_)) => self.stack.push(arg),
-                (Op::BinFloat, Value::Float(_)) => self.stack.push(arg),
-                (Op::BinGet, Value::UInt(idx)) => {
-                    let val = self.memo.get_mut(idx as usize).unwrap().clone();
-                    println!("BINGET loaded: {val}");
-                    self.stack
-                        .push(val);
+                Ok(arg)
+            })
+            .map_err(|e| self.with_context(e, offset, Some(op.clone())))?;
+        Ok((offset, op, arg))
+    }
+
+    fn execute(&mut self, op: Op, arg: Value<'b>) -> Result<bool, PickleError> {
+        match (op, arg.clone()) {
+            (Op::AddItems, _) => {
+                let values = self.pop_mark()?;
+                match &mut *self.top_mut(self.popped_depth + 1)? {
+                    Value::Set(set) => set.extend(values),
+                    other => return Err(mismatch("set", other)),
                 }
-                (Op::BinPersid, _) => {
-                    // Can we ignore this?
-                    //println!("PERSID: {}", self.stack.last().unwrap());
+            }
+            (Op::Append, _) => {
+                let value = self.pop()?;
+                match &mut *self.top_mut(self.popped_depth + 1)? {
+                    Value::List(vec) => vec.push(value),
+                    other => return Err(mismatch("list", other)),
                 }
-                (Op::BinPut, Value::UInt(idx)) => {
-                    self.memo.insert(idx as usize, self.stack.last().unwrap().clone())
+            }
+            (Op::Appends, _) => {
+                let mut values = self.pop_mark()?;
+                match &mut *self.top_mut(self.popped_depth + 1)? {
+                    Value::List(vec) => vec.append(&mut values),
+                    other => return Err(mismatch("list", other)),
                 }
-                (Op::BinUnicode, Value::String(_)) => self.stack.push(arg),
-                (Op::Build, _) => {
-                    let data = self.stack.pop().expect("moo");
-                    let instance = self.stack.pop().expect("moo");
-                    if let (Value::Object(mut inst), Value::Dict(dict)) = (instance, data) {
-                        inst.set_fields(dict);
-                        self.stack.push(Value::Object(inst));
-                    } else {
-                        panic!("Stack ordering was wrong")
+            }
+            (Op::BinBytes, Value::Bytes(_)) => {
+                self.push(arg);
+            }
+            (Op::BinBytes8, Value::Bytes(_)) => self.push(arg),
+            (Op::BinInt, Value::Int(_)) => self.push(arg),
+            (Op::BinInt1, Value::UInt(_)) => self.push(arg),
+            (Op::BinInt2, Value::UInt(_)) => self.push(arg),
+            (Op::BinFloat, Value::Float(_)) => self.push(arg),
+            (Op::BinGet, Value::UInt(idx)) => self.memo_get(idx as usize)?,
+            (Op::BinPersid, _) => {
+                let pid = self.pop()?;
+                self.persistent_load(pid)?;
+            }
+            (Op::BinPut, Value::UInt(idx)) => self.memo_put(idx as usize)?,
+            (Op::BinString, _) => self.push(arg),
+            (Op::BinUnicode, Value::String(_)) => self.push(arg),
+            (Op::BinUnicode8, Value::String(_)) => self.push(arg),
+            (Op::Build, _) => {
+                // State dicts are copied into the instance like
+                // `object.__setstate__` does, so they needn't stay referenced.
+                let data = match self.pop_unshared()? {
+                    Value::Tuple(pair) => {
+                        // Items copied out of the heap are in the tuple.
+                        let depth = std::mem::take(&mut self.popped_depth);
+                        let pair = pair
+                            .into_iter()
+                            .map(|v| self.unshare(v))
+                            .collect::<Result<_, _>>()?;
+                        self.popped_depth = depth.max(self.popped_depth + 1);
+                        Value::Tuple(pair)
                     }
+                    data => data,
+                };
+                let depth = state_depth(&data, self.popped_depth);
+                match self.top_mut(depth)? {
+                    // Callables are objects made by REDUCE without an extension.
+                    Value::Object(inst) | Value::Callable(inst, _) => inst.set_state(data)?,
+                    other => return Err(mismatch("object", other)),
                 }
-                (Op::EmptyDict, _) => self.stack.push(Value::Dict(HashMap::new())),
-                (Op::EmptyList, _) => self.stack.push(Value::List(Vec::new())),
-                (Op::EmptyTuple, _) => self.stack.push(Value::Tuple(Vec::new())),
-                (Op::Frame, Value::ULong(frame_size)) => {
-                    self.is_framed = true;
-                    self.set_working_frame(frame_size as usize);
-                },
-                (Op::GlobalOpcode, Value::String(s)) => {
-                   let v: Vec<&str> = s.split('\n').collect(); 
-                   self.stack.push(Value::Object(Instance::new(v[1].to_string(), v[0].to_string())));
+            }
+            (Op::Float, Value::Float(_)) => self.push(arg),
+            (Op::ByteArray8, Value::ByteArray(_)) => self.push(arg),
+            (Op::Dict, _) => {
+                let values = self.pop_mark()?;
+                let pairs = into_pairs(values)?;
+                self.push(Value::Dict(pairs.collect()));
+            }
+            (Op::Dup, _) => {
+                let top = self.top_slot()?.clone();
+                let depth = self.depths.last().copied().unwrap_or(0);
+                self.push_at(top, depth);
+            }
+            (Op::EmptyDict, _) => self.push(Value::Dict(HashMap::new())),
+            (Op::EmptyList, _) => self.push(Value::List(Vec::new())),
+            (Op::EmptySet, _) => self.push(Value::Set(HashSet::new())),
+            (Op::EmptyTuple, _) => self.push(Value::Tuple(Vec::new())),
+            // Entered by next_instruction.
+            (Op::Frame, _) => {}
+            (Op::FrozenSet, _) => {
+                let values = self.pop_mark()?;
+                self.push(Value::FrozenSet(values.into_iter().collect()));
+            }
+            (Op::Get, Value::UInt(idx)) => self.memo_get(idx as usize)?,
+            (Op::GlobalOpcode, Value::String(s)) => {
+               let v: Vec<&str> = s.split('\n').collect();
+               let inst = self.find_class(v[0].to_string(), v[1].to_string())?;
+               self.push(Value::Object(inst));
+            }
+            (Op::Ext1 | Op::Ext2 | Op::Ext4, Value::Int(code)) => {
+                let inst = self.find_extension(code)?;
+                self.push(Value::Object(inst));
+            }
+            // Legacy instantiation: class from the argument, args from the mark.
+            (Op::Inst, Value::String(s)) => {
+                let args = self.pop_mark()?;
+                let v: Vec<&str> = s.split('\n').collect();
+                let inst = self.find_class(v[0].to_string(), v[1].to_string())?;
+                self.instantiate(inst, args, None, self.popped_depth + 1)?;
+            }
+            (Op::Int, _) => self.push(arg),
+            (Op::Long, _) => self.push(arg),
+            (Op::List, _) => {
+                let values = self.pop_mark()?;
+                self.push(Value::List(values));
+            }
+            (Op::Long1, _) => self.push(arg),
+            (Op::Long4, _) => self.push(arg),
+            (Op::LongBinGet, Value::UInt(idx)) => self.memo_get(idx as usize)?,
+            (Op::LongBinPut, Value::UInt(idx)) => self.memo_put(idx as usize)?,
+            (Op::Mark, _) => self.marks.push(self.stack.len()),
+            // Like CPython, MEMOIZE uses the memo size as index
+            // even if earlier PUTs left gaps.
+            (Op::Memoize, _) => self.memo_put(self.memo.len())?,
+            (Op::NewFalse, _) => {
+                self.push(Value::Bool(false));
+            }
+            (Op::NewObj, _) => {
+                let args = self.pop()?;
+                let instance = self.pop_unshared()?;
+                match (instance, args) {
+                    // Only the args are kept, `cls.__new__` isn't replaced
+                    // by extensions. They are one level deeper in their tuple.
+                    (Value::Object(mut inst), Value::Tuple(args)) => {
+                        inst.args = args;
+                        self.push_at(Value::Object(inst), self.popped_depth);
+                    }
+                    (Value::Object(_), args) => return Err(mismatch("tuple", &args)),
+                    (instance, _) => return Err(mismatch("object", &instance)),
                 }
-                (Op::Long1, Value::UInt(len)) => {
-                    let mut bytes = self.read_n(len as usize);
-                    while bytes.len() < 16 {
-                        bytes.push(0);
+            }
+            (Op::NewObjEx, _) => {
+                let kwargs = self.pop_unshared()?;
+                let args = self.pop()?;
+                let instance = self.pop_unshared()?;
+                match (instance, args, kwargs) {
+                    (Value::Object(inst), Value::Tuple(args), Value::Dict(kwargs)) => {
+                        let kwargs = kwargs
+                            .into_iter()
+                            .map(|(k, v)| match k {
+                                Value::String(k) => Ok((k, v)),
+                                k => Err(mismatch("str keyword", &k)),
+                            })
+                            .collect::<Result<_, _>>()?;
+                        self.instantiate(inst, args, Some(kwargs), self.popped_depth)?;
                     }
-                    let buf: [u8; 16] = bytes.try_into().expect("wow");
-                    let long = i128::from_le_bytes(buf);
-                    self.stack.push(Value::Long(long));
+                    (Value::Object(_), Value::Tuple(_), kwargs) => {
+                        return Err(mismatch("dict", &kwargs))
+                    }
+                    (Value::Object(_), args, _) => return Err(mismatch("tuple", &args)),
+                    (instance, _, _) => return Err(mismatch("object", &instance)),
                 }
-                (Op::Mark, _) => self.stack.push(Value::Mark),
-                (Op::Memoize, _) => {
-                    let val = self.stack.last().unwrap();
-                    self.memo.push(val.clone());
+            }
+            (Op::NewTrue, _) => {
+                self.push(Value::Bool(true));
+            }
+            (Op::NextBuffer, _) => {
+                let data = self.buffers.pop_front().ok_or(PickleError::MissingBuffer)?;
+                self.push(Value::PickleBuffer { data, readonly: false });
+            }
+            (Op::None, _) => self.push(Value::None),
+            (Op::Persid, Value::String(_)) => self.persistent_load(arg)?,
+            // Popping the last value after a mark pops the mark itself.
+            (Op::Pop, _) => {
+                if self.stack.len() > self.stack_floor() {
+                    self.truncate(self.stack.len() - 1);
+                } else {
+                    self.pop_mark()?;
                 }
-                (Op::NewFalse, _) => {
-                    self.stack.push(Value::Bool(false));
+            }
+            // Like INST, but the class is the first marked item.
+            (Op::Obj, _) => {
+                let mut args = self.pop_mark()?;
+                if args.is_empty() {
+                    return Err(PickleError::StackUnderflow);
                 }
-                (Op::NewObj, _) => {
-                    let args = self.stack.pop().expect("moo");
-                    let instance = self.stack.pop().expect("moo");
-                    if let (Value::Object(mut inst), Value::Tuple(args)) = (instance, args) {
-                        inst.args = args;
-                        self.stack.push(Value::Object(inst));
-                    } else {
-                        panic!("Stack ordering was wrong");
+                match self.unshare(args.remove(0))? {
+                    Value::Object(inst) => {
+                        self.instantiate(inst, args, None, self.popped_depth + 1)?
                     }
+                    cls => return Err(mismatch("class", &cls)),
                 }
-                (Op::NewTrue, _) => {
-                    self.stack.push(Value::Bool(true));
-                }
-                (Op::None, _) => self.stack.push(Value::None),
-                (Op::Reduce, _) => {
-                    let pytuple = self.stack.pop().expect("bark");
-                    let callable = self.stack.pop().expect("bark");
-
-                    if let (Value::Object(inst), _) = (callable, pytuple.clone()) {
-                        if let Some(fnc) = self.extensions.get_mut(&inst.as_key())  {
-                            println!("FOUND extension for {}", inst.as_key());
-                            self.stack.push(fnc(pytuple));
-                        } else {
-                            println!("did not find extension for {}", inst.as_key());
-                            self.stack.push(Value::Callable(inst, Box::new(pytuple)));
-                        }
-                    } else {
-                        panic!("This should not happen.");
-                    }
+            }
+            (Op::PopMark, _) => {
+                let mark = self.marks.pop().ok_or(PickleError::StackUnderflow)?;
+                self.truncate(mark);
+            }
+            (Op::Proto, Value::UInt(v)) => self.version = check_version(v as u8)?,
+            (Op::Put, Value::UInt(idx)) => self.memo_put(idx as usize)?,
+            // Buffers that are already read-only, like bytes, are kept as is.
+            (Op::ReadonlyBuffer, _) => match &mut *self.top_mut(0)? {
+                Value::PickleBuffer { readonly, .. } => *readonly = true,
+                Value::Bytes(_) => {}
+                top @ Value::ByteArray(_) => {
+                    let data = std::mem::replace(top, Value::None).as_bytearray().unwrap();
+                    *top = Value::PickleBuffer { data: data.into(), readonly: true };
                 }
-                (Op::SetItems, _) => {
-                    let values = {
-                        // Array of (K, V)
-                        let mut kv_values: Vec<(Value, Value)> = Vec::new();
-                        loop {
-                            let v = self.stack.pop().unwrap();
-                            if v == Value::Mark {
-                                break;
-                            }
-                            let k = self.stack.pop().unwrap();
-                            kv_values.insert(0, (k, v));
-                        }
-                        kv_values
-                    };
-                    if let Some(Value::Dict(map)) = self.stack.last_mut() {
-                        for (k, v) in values.into_iter() {
-                            map.insert(k, v);
-                        }
-                    } else {
-                        panic!("Stack ordering was wrong");
+                top => return Err(mismatch("buffer", top)),
+            },
+            (Op::Reduce, _) => {
+                let mut pytuple = self.pop()?;
+                let callable = self.pop_unshared()?;
+
+                if let Value::Object(inst) = callable {
+                    match self.call_extension(&inst, None, &mut pytuple) {
+                        Some(result) => self.push_measured(result?),
+                        None => self.push(Value::Callable(inst, Box::new(pytuple))),
                     }
+                } else {
+                    return Err(mismatch("callable", &callable));
                 }
-                (Op::ShortBinunicde, Value::String(_)) => self.stack.push(arg),
-                // Push a global object on the stack.
-                (Op::StackGlobal, _) => {
-                    let name = self.stack.pop().unwrap();
-                    let module = self.stack.pop().unwrap();
-                    if let (Value::String(name), Value::String(module)) = (name, module) {
-                        self.stack.push(Value::Object(Instance::new(name, module)))
-                    } else {
-                        panic!("Stack ordering was wrong");
+            }
+            (Op::SetItem, _) => {
+                let v = self.pop()?;
+                let k = self.pop()?;
+                match &mut *self.top_mut(self.popped_depth + 1)? {
+                    Value::Dict(map) => {
+                        map.insert(k, v);
                     }
+                    other => return Err(mismatch("dict", other)),
                 }
-                // Create a tuple from all topmost values in stack
-                // delimited by a Mark object.
-                (Op::Tuple, _) => {
-                    let values = {
-                        let mut values: Vec<Value> = Vec::new();
-                        loop {
-                            let v = self.stack.pop().unwrap();
-                            if v == Value::Mark {
-                                break;
-                            }
-                            values.insert(0, v);
-                        }
-                        values
-                    };
-                    self.stack.push(Value::Tuple(values));
-                }
-                (Op::Tuple1, _) => {
-                    let a = self.stack.pop().expect("meow");
-                    self.stack.push(Value::Tuple(vec![a]));
+            }
+            (Op::SetItems, _) => {
+                let values = self.pop_mark()?;
+                let pairs = into_pairs(values)?;
+                match &mut *self.top_mut(self.popped_depth + 1)? {
+                    Value::Dict(map) => map.extend(pairs),
+                    other => return Err(mismatch("dict", other)),
                 }
-                (Op::Tuple2, _) => {
-                    let b = self.stack.pop().expect("meow");
-                    let a = self.stack.pop().expect("meow");
-                    self.stack.push(Value::Tuple(vec![a, b]));
+            }
+            (Op::ShortBinbytes, Value::Bytes(_)) => self.push(arg),
+            (Op::ShortBinstring, _) => self.push(arg),
+            (Op::ShortBinunicde, Value::String(_)) => self.push(arg),
+            // Push a global object on the stack.
+            (Op::StackGlobal, _) => {
+                let name = self.pop()?;
+                let module = self.pop()?;
+                match (name, module) {
+                    (Value::String(name), Value::String(module)) => {
+                        let inst = self.find_class(module, name)?;
+                        self.push(Value::Object(inst))
+                    }
+                    (Value::String(_), module) => return Err(mismatch("str", &module)),
+                    (name, _) => return Err(mismatch("str", &name)),
                 }
-                (Op::Tuple3, _) => {
-                    let c = self.stack.pop().expect("meow");
-                    let b = self.stack.pop().expect("meow");
-                    let a = self.stack.pop().expect("meow");
-                    self.stack.push(Value::Tuple(vec![a, b, c]));
+            }
+            (Op::Stop, _) => return Ok(false),
+            // Create a tuple from all topmost values in stack
+            // delimited by the last mark.
+            (Op::String, _) => self.push(arg),
+            (Op::Tuple, _) => {
+                let values = self.pop_mark()?;
+                self.push(Value::Tuple(values));
+            }
+            (Op::Tuple1, _) => {
+                let a = self.pop()?;
+                self.push(Value::Tuple(vec![a]));
+            }
+            (Op::Tuple2, _) => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(Value::Tuple(vec![a, b]));
+            }
+            (Op::Tuple3, _) => {
+                let c = self.pop()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(Value::Tuple(vec![a, b, c]));
+            }
+            (Op::Unicode, Value::String(_)) => self.push(arg),
+            (op, _) => return Err(PickleError::UnsupportedOpcode(op)),
+        }
+        Ok(true)
+    }
+}
+
+// Values shown with an error, and how many characters of each.
+const SNAPSHOT_DEPTH: usize = 3;
+const SNAPSHOT_WIDTH: usize = 64;
+
+// Display `value` for an error, cut at SNAPSHOT_WIDTH characters.
+// Writing stops there, so a large list costs no more than a small one.
+fn snapshot(value: &dyn std::fmt::Display) -> String {
+    struct Bounded(String, usize);
+
+    impl std::fmt::Write for Bounded {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            for c in s.chars() {
+                if self.1 == SNAPSHOT_WIDTH {
+                    return Err(std::fmt::Error);
                 }
-                _ => unimplemented!(),
+                self.0.push(c);
+                self.1 += 1;
             }
-            return true;
+            Ok(())
         }
-        false
     }
+
+    let mut out = Bounded(String::new(), 0);
+    match std::fmt::Write::write_fmt(&mut out, format_args!("{value}")) {
+        Ok(()) => out.0,
+        Err(_) => out.0 + "...",
+    }
+}
+
+fn check_limit(limit: &'static str, value: u64, max: u64) -> Result<(), PickleError> {
+    if value > max {
+        return Err(PickleError::LimitExceeded { limit, value, max });
+    }
+    Ok(())
+}
+
+// Values that opcodes can change after they were memoized, and so
+// get shared in object graph mode. Globals are objects too.
+fn is_mutable(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::List(_)
+            | Value::Dict(_)
+            | Value::Set(_)
+            | Value::ByteArray(_)
+            | Value::Object(_)
+            | Value::Callable(_, _)
+    )
+}
+
+// Nesting depth of a value, counted up to `limit + 1`. Iterative so
+// that measuring a deep value can't overflow the stack itself. The
+// values behind references are checked on their own.
+fn measure_depth(value: &Value, limit: usize) -> usize {
+    let mut deepest = 0;
+    let mut todo = vec![(value, 0)];
+    while let Some((value, depth)) = todo.pop() {
+        let Some(children) = children(value) else {
+            continue;
+        };
+        deepest = deepest.max(depth + 1);
+        if deepest > limit {
+            break;
+        }
+        todo.extend(children.map(|child| (child, depth + 1)));
+    }
+    deepest
+}
+
+// Depth an instance has at least once BUILD set `state` on it, from
+// the depth of the state. Follows `Instance::set_state`: dicts are
+// copied into the instance, so their values are as deep in it as in
+// them, and other states are kept whole.
+fn state_depth(state: &Value, depth: usize) -> usize {
+    match state {
+        Value::None => 0,
+        Value::Dict(_) => depth,
+        Value::Tuple(pair)
+            if pair.len() == 2
+                && matches!(pair[0], Value::Dict(_) | Value::None)
+                && matches!(pair[1], Value::Dict(_) | Value::None) =>
+        {
+            depth - 1
+        }
+        _ => depth + 1,
+    }
+}
+
+// Number of values a clone of `value` copies, counted up to `limit + 1`.
+// Cloning a reference only copies the reference.
+fn count_values(value: &Value, limit: u64) -> u64 {
+    let mut count = 0;
+    let mut todo = vec![value];
+    while let Some(value) = todo.pop() {
+        count += 1;
+        if count > limit {
+            break;
+        }
+        todo.extend(children(value).into_iter().flatten());
+    }
+    count
+}
+
+// Python 2 str is a byte string: keep it as text when it is
+// valid utf-8 and as bytes otherwise.
+fn py2_string(bytes: Vec<u8>) -> Value<'static> {
+    match String::from_utf8(bytes) {
+        Ok(s) => Value::String(s),
+        Err(e) => Value::Bytes(e.into_bytes()),
+    }
+}
+
+// Python ints are unbounded. Keep them as i128 when they fit,
+// so only the really large ones end up as BigInt.
+fn int_value(big: BigInt) -> Value<'static> {
+    match big.to_i128() {
+        Some(v) => Value::Long(v),
+        None => Value::BigInt(big),
+    }
+}
+
+// Decode a decimal integer written by INT or LONG.
+fn text_int(line: &[u8]) -> Result<Value<'static>, PickleError> {
+    match parse_number::<i128>(line) {
+        Ok(v) => Ok(Value::Long(v)),
+        Err(_) => Ok(int_value(parse_number(line)?)),
+    }
+}
+
+// Decode the little-endian two's complement payload of LONG1 and LONG4.
+fn binary_int(bytes: &[u8]) -> Value<'static> {
+    if bytes.len() > 16 {
+        return int_value(BigInt::from_signed_bytes_le(bytes));
+    }
+    // Sign extend into an i128.
+    let fill = if bytes.last().is_some_and(|b| b & 0x80 != 0) { 0xff } else { 0 };
+    let mut buf = [fill; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Value::Long(i128::from_le_bytes(buf))
+}
+
+// Parse a decimal number from a protocol 0 text argument.
+fn parse_number<T: std::str::FromStr>(line: &[u8]) -> Result<T, PickleError> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| {
+            PickleError::Malformed(format!(
+                "invalid number {:?}",
+                String::from_utf8_lossy(line)
+            ))
+        })
+}
+
+// Pair up alternating keys and values, as pushed for DICT and SETITEMS.
+fn into_pairs<'b>(
+    values: Vec<Value<'b>>,
+) -> Result<impl Iterator<Item = (Value<'b>, Value<'b>)>, PickleError> {
+    if !values.len().is_multiple_of(2) {
+        return Err(PickleError::Malformed(
+            "odd number of items for a dict".to_string(),
+        ));
+    }
+    let mut values = values.into_iter();
+    Ok(std::iter::from_fn(move || Some((values.next()?, values.next()?))))
+}
+
+fn check_version(version: u8) -> Result<u8, PickleError> {
+    match version {
+        0..=5 => Ok(version),
+        v => Err(PickleError::UnsupportedProtocol(v)),
+    }
+}
+
+fn mismatch(expected: &'static str, found: &Value) -> PickleError {
+    PickleError::TypeMismatch {
+        expected,
+        found: found.type_name(),
+    }
+}
+
+fn straddles_frame() -> PickleError {
+    PickleError::Malformed("opcode straddles the end of a frame".to_string())
 }
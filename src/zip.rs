@@ -0,0 +1,165 @@
+// Just enough of the zip format to find the pickles inside a torch
+// archive, which stores its entries uncompressed.
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::PickleError;
+
+pub const LOCAL_HEADER_SIGNATURE: [u8; 4] = *b"PK\x03\x04";
+const CENTRAL_HEADER_SIGNATURE: [u8; 4] = *b"PK\x01\x02";
+const END_SIGNATURE: [u8; 4] = *b"PK\x05\x06";
+const ZIP64_LOCATOR_SIGNATURE: [u8; 4] = *b"PK\x06\x07";
+const ZIP64_END_SIGNATURE: [u8; 4] = *b"PK\x06\x06";
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const END_SIZE: u64 = 22;
+const MAX_COMMENT: u64 = 0xffff;
+const STORED: u16 = 0;
+
+pub struct Entry {
+    pub name: String,
+    compression: u16,
+    size: u64,
+    header_offset: u64,
+}
+
+// List the entries of the central directory.
+pub fn entries<R: Read + Seek>(r: &mut R) -> Result<Vec<Entry>, PickleError> {
+    let (mut count, dir_offset) = find_directory(r)?;
+    let mut entries = vec![];
+    r.seek(SeekFrom::Start(dir_offset))?;
+    while count > 0 {
+        let header: [u8; 46] = read_array(r)?;
+        if header[..4] != CENTRAL_HEADER_SIGNATURE {
+            return Err(malformed("bad central directory header"));
+        }
+        let mut size = le32(&header, 24) as u64;
+        let mut header_offset = le32(&header, 42) as u64;
+        let name_len = le16(&header, 28) as usize;
+        let extra_len = le16(&header, 30) as usize;
+        let comment_len = le16(&header, 32) as i64;
+        let mut name = vec![0; name_len];
+        r.read_exact(&mut name)?;
+        let mut extra = vec![0; extra_len];
+        r.read_exact(&mut extra)?;
+        r.seek(SeekFrom::Current(comment_len))?;
+
+        // Zip64 values are only present for the fields that overflowed,
+        // in this order.
+        if let Some(mut zip64) = zip64_extra(&extra) {
+            let mut next = || -> Result<u64, PickleError> {
+                let (value, rest) = zip64
+                    .split_first_chunk::<8>()
+                    .ok_or_else(|| malformed("truncated zip64 extra field"))?;
+                zip64 = rest;
+                Ok(u64::from_le_bytes(*value))
+            };
+            if size == 0xffff_ffff {
+                size = next()?;
+            }
+            if le32(&header, 20) == 0xffff_ffff {
+                next()?;
+            }
+            if header_offset == 0xffff_ffff {
+                header_offset = next()?;
+            }
+        }
+
+        entries.push(Entry {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            compression: le16(&header, 10),
+            size,
+            header_offset,
+        });
+        count -= 1;
+    }
+    Ok(entries)
+}
+
+// Seek to the data of a stored entry, returning its size.
+pub fn open_entry<R: Read + Seek>(r: &mut R, entry: &Entry) -> Result<u64, PickleError> {
+    if entry.compression != STORED {
+        return Err(malformed(&format!(
+            "{} uses unsupported compression method {}",
+            entry.name, entry.compression
+        )));
+    }
+    r.seek(SeekFrom::Start(entry.header_offset))?;
+    let header: [u8; 30] = read_array(r)?;
+    if header[..4] != LOCAL_HEADER_SIGNATURE {
+        return Err(malformed("bad local file header"));
+    }
+    let skip = le16(&header, 26) as i64 + le16(&header, 28) as i64;
+    r.seek(SeekFrom::Current(skip))?;
+    Ok(entry.size)
+}
+
+// Entry count and offset of the central directory, from the
+// end of central directory record or its zip64 counterpart.
+fn find_directory<R: Read + Seek>(r: &mut R) -> Result<(u64, u64), PickleError> {
+    let len = r.seek(SeekFrom::End(0))?;
+    let tail_len = len.min(END_SIZE + MAX_COMMENT);
+    r.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    r.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(END_SIZE as usize - 1))
+        .rev()
+        .find(|&i| tail[i..i + 4] == END_SIGNATURE)
+        .ok_or_else(|| malformed("end of central directory not found"))?;
+    let count = le16(&tail, end + 10) as u64;
+    let offset = le32(&tail, end + 16) as u64;
+    if count != 0xffff && offset != 0xffff_ffff {
+        return Ok((count, offset));
+    }
+
+    let end_offset = len - tail_len + end as u64;
+    r.seek(SeekFrom::Start(
+        end_offset
+            .checked_sub(20)
+            .ok_or_else(|| malformed("zip64 locator not found"))?,
+    ))?;
+    let locator: [u8; 20] = read_array(r)?;
+    if locator[..4] != ZIP64_LOCATOR_SIGNATURE {
+        return Err(malformed("zip64 locator not found"));
+    }
+    r.seek(SeekFrom::Start(le64(&locator, 8)))?;
+    let end64: [u8; 56] = read_array(r)?;
+    if end64[..4] != ZIP64_END_SIGNATURE {
+        return Err(malformed("bad zip64 end of central directory"));
+    }
+    Ok((le64(&end64, 32), le64(&end64, 48)))
+}
+
+fn zip64_extra(mut extra: &[u8]) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let id = le16(extra, 0);
+        let len = (le16(extra, 2) as usize).min(extra.len() - 4);
+        if id == ZIP64_EXTRA_ID {
+            return Some(&extra[4..4 + len]);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn read_array<const L: usize, R: Read>(r: &mut R) -> Result<[u8; L], PickleError> {
+    let mut buf = [0; L];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn le16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn le32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn le64(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+fn malformed(reason: &str) -> PickleError {
+    PickleError::Malformed(format!("zip archive: {reason}"))
+}